@@ -0,0 +1,86 @@
+// ANSI escape sequences used when coloring the output. When coloring
+// is disabled, the helpers below write out the text as-is.
+use std::io;
+use std::io::prelude::*;
+use conf::Conf;
+
+pub const RESET : &[u8] = b"\x1b[0m";
+pub const HEADER : &[u8] = b"\x1b[1m";
+pub const HUNK : &[u8] = b"\x1b[36m";
+pub const REMOVED : &[u8] = b"\x1b[31m";
+pub const ADDED : &[u8] = b"\x1b[32m";
+// Changed fragments of context lines.
+pub const REMOVED_SPAN : &[u8] = b"\x1b[7;31m";
+pub const ADDED_SPAN : &[u8] = b"\x1b[7;32m";
+// Character class summaries.
+pub const CLASS : &[u8] = b"\x1b[1;33m";
+
+pub fn start(out : &mut Write, conf : &Conf, style : &[u8]) -> io::Result<()> {
+    if conf.color {
+        out.write_all(style)
+    } else {
+        Ok (())
+    }
+}
+
+pub fn end(out : &mut Write, conf : &Conf) -> io::Result<()> {
+    if conf.color {
+        out.write_all(RESET)
+    } else {
+        Ok (())
+    }
+}
+
+/// Write out a whole line, prefix included. The style is reset before
+/// the EOL, so that it doesn't bleed into whatever comes next.
+pub fn write_line(out : &mut Write, conf : &Conf, style : &[u8],
+                  prefix : &[u8], line : &[u8]) -> io::Result<()> {
+    if !conf.color {
+        out.write_all(prefix)?;
+        return out.write_all(line)
+    }
+    let (text, eol) = match line.last() {
+        Some (b'\n') => (&line[..(line.len() - 1)], &b"\n"[..]),
+        _ => (line, &b""[..]),
+    };
+    let mut buf = Vec::with_capacity(style.len() + prefix.len() + line.len() + RESET.len());
+    buf.extend_from_slice(style);
+    buf.extend_from_slice(prefix);
+    buf.extend_from_slice(text);
+    buf.extend_from_slice(RESET);
+    buf.extend_from_slice(eol);
+    out.write_all(&buf)
+}
+
+/// Like write_line, but also highlight the bytes flagged as changed
+/// (which needn't cover the EOL) with span_style.
+pub fn write_line_spans(out : &mut Write, conf : &Conf, style : &[u8], span_style : &[u8],
+                        prefix : &[u8], line : &[u8], changed : &[bool]) -> io::Result<()> {
+    if !conf.color {
+        return write_line(out, conf, style, prefix, line)
+    }
+    let (text, eol) = match line.last() {
+        Some (b'\n') => (&line[..(line.len() - 1)], &b"\n"[..]),
+        _ => (line, &b""[..]),
+    };
+    let mut buf = vec![];
+    buf.extend_from_slice(style);
+    buf.extend_from_slice(prefix);
+    let mut in_span = false;
+    for (i, &b) in text.iter().enumerate() {
+        let span = changed.get(i) == Some (&true);
+        if span != in_span {
+            if span {
+                buf.extend_from_slice(span_style)
+            } else {
+                buf.extend_from_slice(RESET);
+                buf.extend_from_slice(style)
+            }
+            in_span = span
+        }
+        buf.push(b)
+    }
+    buf.extend_from_slice(RESET);
+    buf.extend_from_slice(eol);
+    out.write_all(&buf)
+}
@@ -1,3 +1,9 @@
+use std::borrow::Cow;
+use std::slice;
+use std::str::FromStr;
+use regex;
+use regex::bytes::{Regex, RegexBuilder, RegexSet};
+
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum CharacterClassExpansion {
@@ -5,28 +11,314 @@ pub enum CharacterClassExpansion {
     Wide,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub enum ContextLineTokenization {
     Char,
-    Word,
+    // Tokens are split at word boundaries, as found by the RE (which is
+    // always \b, compiled once here).
+    Word (Regex),
+    // Tokens are the matches of the RE, along with the text between them.
+    Re (Regex),
 }
 
 impl ContextLineTokenization {
     pub fn allowed_values() -> Vec<&'static str> {
-        vec!["word", "char"]
+        vec!["word", "char", "re:PATTERN"]
+    }
+    pub fn validate(s : &str) -> Result<(), String> {
+        ContextLineTokenization::new(s).map(|_| ())
     }
-    pub fn new(s : &str) -> ContextLineTokenization {
+    pub fn word() -> ContextLineTokenization {
+        ContextLineTokenization::Word (Regex::new(r"\b").unwrap())
+    }
+    pub fn new(s : &str) -> Result<ContextLineTokenization, String> {
         use self::ContextLineTokenization::*;
         if s == "word" {
-            Word
+            Ok (ContextLineTokenization::word())
         } else if s == "char" {
-            Char
+            Ok (Char)
+        } else if let Some (re) = s.strip_prefix("re:") {
+            Regex::new(re).map(Re).map_err(|err| err.to_string())
         } else {
-            panic!("Unsupported value: `{}`", s);
+            Err (format!("possible values: {}",
+                         ContextLineTokenization::allowed_values().join(", ")))
+        }
+    }
+}
+
+// A named character class for summarizing changes in the cc context
+// formats, in addition to the predefined ones.
+#[derive(Clone)]
+pub struct CcClass {
+    pub name : String,
+    // Finds the members of the class in a line.
+    pub re : Regex,
+}
+
+impl CcClass {
+    pub fn builtin_names() -> Vec<&'static str> {
+        vec!["hex", "uuid", "timestamp"]
+    }
+    pub fn new(name : &str, re : &str) -> Result<CcClass, ::regex::Error> {
+        Ok (CcClass {
+            name : name.to_string(),
+            re : Regex::new(re)?,
+        })
+    }
+    pub fn builtin(name : &str) -> Option<CcClass> {
+        let re = if name == "hex" {
+            r"\b0[xX][[:xdigit:]]+\b"
+        } else if name == "uuid" {
+            r"\b[[:xdigit:]]{8}-[[:xdigit:]]{4}-[[:xdigit:]]{4}-[[:xdigit:]]{4}-[[:xdigit:]]{12}\b"
+        } else if name == "timestamp" {
+            r"\b\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(\.\d+)?(Z|[+-]\d{2}:?\d{2})?"
+        } else {
+            return None
+        };
+        Some (CcClass::new(name, re).unwrap())
+    }
+}
+
+// The user-defined character classes, in the order they are tried in.
+#[derive(Clone)]
+pub struct CcClasses {
+    classes : Vec<CcClass>,
+    // Matches the members of each class, in whole.
+    whole : RegexSet,
+}
+
+impl CcClasses {
+    pub fn new(classes : Vec<CcClass>) -> CcClasses {
+        let whole = classes.iter().map(|cl| format!("^(?:{})$", cl.re.as_str()));
+        // The REs have already been compiled on their own.
+        let whole = RegexSet::new(whole).unwrap();
+        CcClasses {
+            classes,
+            whole,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.classes.is_empty()
+    }
+    pub fn iter(&self) -> slice::Iter<'_, CcClass> {
+        self.classes.iter()
+    }
+    pub fn name(&self, i : usize) -> &str {
+        &self.classes[i].name
+    }
+    /// The first of the classes that s is a member of, in whole.
+    pub fn class_of(&self, s : &[u8]) -> Option<usize> {
+        if self.classes.is_empty() {
+            return None
+        }
+        self.whole.matches(s).into_iter().next()
+    }
+}
+
+// A sed-like s/RE/REPLACEMENT/[g] rule, rewriting the selected part
+// of the lines before they are compared.
+#[derive(Clone)]
+pub struct Substitution {
+    pub re : Regex,
+    // In the syntax of the regex crate, i.e. with ${1} for \1.
+    pub replacement : Vec<u8>,
+    // Replace all matches, not just the first one.
+    pub global : bool,
+}
+
+// Split s on the unescaped occurences of delim. Escapes are left in
+// place, for the caller to interpret.
+fn split_unescaped(s : &str, delim : char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+    while let Some (c) = chars.next() {
+        if c == '\\' {
+            parts.last_mut().unwrap().push(c);
+            if let Some (c) = chars.next() {
+                parts.last_mut().unwrap().push(c)
+            }
+        } else if c == delim {
+            parts.push(String::new())
+        } else {
+            parts.last_mut().unwrap().push(c)
+        }
+    }
+    parts
+}
+
+impl Substitution {
+    pub fn parse(s : &str) -> Result<Substitution, String> {
+        let mut chars = s.chars();
+        let delim = match (chars.next(), chars.next()) {
+            (Some ('s'), Some (d)) if !d.is_alphanumeric() && d != '\\' && d != '\n' => d,
+            _ => return Err (format!("Substitution `{}` is not of the form s/RE/REPLACEMENT/", s)),
+        };
+        let parts = split_unescaped(chars.as_str(), delim);
+        if parts.len() != 3 {
+            return Err (format!("Substitution `{}` is not of the form s/RE/REPLACEMENT/", s))
+        }
+        let global = match &parts[2][..] {
+            "" => false,
+            "g" => true,
+            flags => return Err (format!("Unsupported substitution flags `{}`", flags)),
+        };
+        let escaped_delim = format!("\\{}", delim);
+        let re = parts[0].replace(&escaped_delim, &regex::escape(&delim.to_string()));
+        let re = match RegexBuilder::new(&re).multi_line(true).build() {
+            Ok (re) => re,
+            Err (err) => return Err (format!("Could not compile regular expression `{}`: {}",
+                                             re, err)),
+        };
+        // Translate the sed-style references to capture groups.
+        let mut replacement = String::new();
+        let mut chars = parts[1].chars();
+        while let Some (c) = chars.next() {
+            match c {
+                '&' => replacement.push_str("${0}"),
+                '\\' => match chars.next() {
+                    Some (d) if d.is_ascii_digit() => {
+                        replacement.push_str(&format!("${{{}}}", d))
+                    },
+                    Some ('$') => replacement.push_str("$$"),
+                    Some (c) => replacement.push(c),
+                    None => replacement.push('\\'),
+                },
+                // A $ is just a $ to sed.
+                '$' => replacement.push_str("$$"),
+                c => replacement.push(c),
+            }
+        }
+        Ok (Substitution {
+            re,
+            replacement : replacement.into_bytes(),
+            global,
+        })
+    }
+    // The substitutions of --auto-ignore, which replace common volatile
+    // values with placeholders. More specific formats need to come first,
+    // e.g. timestamps before times of day.
+    pub fn auto_ignore_rules() -> Vec<&'static str> {
+        vec![
+            r"s/\b\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:[.,]\d+)?(?:Z|[+-]\d{2}:?\d{2})?/<timestamp>/g",
+            r"s/\b(?:Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec) [ \d]\d \d{2}:\d{2}:\d{2}(?:\.\d+)?\b/<timestamp>/g",
+            r"s/\b\d{2}:\d{2}:\d{2}(?:[.,]\d+)?\b/<timestamp>/g",
+            r"s/^\[ *\d+\.\d+\]/[<timestamp>]/g",
+            r"s/\b[[:xdigit:]]{8}-[[:xdigit:]]{4}-[[:xdigit:]]{4}-[[:xdigit:]]{4}-[[:xdigit:]]{12}\b/<uuid>/g",
+            // Compressed IPv6 addresses need a decimal digit in a group
+            // next to the ::, or C++ names like Cafe::feed would match.
+            concat!(r"s/\b(?:[[:xdigit:]]{1,4}:){7}[[:xdigit:]]{1,4}\b",
+                    r"|\b(?:[[:xdigit:]]{1,4}:){0,6}(?:\d[[:xdigit:]]{0,3}|[[:xdigit:]]\d[[:xdigit:]]{0,2}|[[:xdigit:]]{2}\d[[:xdigit:]]?|[[:xdigit:]]{3}\d)::(?:[[:xdigit:]]{1,4}(?::[[:xdigit:]]{1,4}){0,6}\b)?",
+                    r"|\b(?:[[:xdigit:]]{1,4}:){0,6}[[:xdigit:]]{1,4}::(?:\d[[:xdigit:]]{0,3}|[[:xdigit:]]\d[[:xdigit:]]{0,2}|[[:xdigit:]]{2}\d[[:xdigit:]]?|[[:xdigit:]]{3}\d)(?::[[:xdigit:]]{1,4}){0,6}\b",
+                    r"|\B::(?:\d[[:xdigit:]]{0,3}|[[:xdigit:]]\d[[:xdigit:]]{0,2}|[[:xdigit:]]{2}\d[[:xdigit:]]?|[[:xdigit:]]{3}\d)(?::[[:xdigit:]]{1,4}){0,6}\b",
+                    r"/<ipv6>/g"),
+            r"s/\b(?:\d{1,3}\.){3}\d{1,3}\b/<ipv4>/g",
+            r"s/\b0[xX][[:xdigit:]]+\b/<hex>/g",
+            r"s/(\w)\[\d+\]:/\1[<pid>]:/g",
+            r"s/\[pid +\d+\]/[<pid>]/g",
+            r"s/\b\d+(?:\.\d+)?(?:ns|us|µs|ms|s|min|h)\b|<\d+\.\d+>/<duration>/g",
+        ]
+    }
+    pub fn apply<'a>(&self, key : &'a [u8]) -> Cow<'a, [u8]> {
+        if self.global {
+            self.re.replace_all(key, &self.replacement[..])
+        } else {
+            self.re.replace(key, &self.replacement[..])
         }
     }
 }
 
+#[derive(Clone, Copy)]
+pub enum ToleranceBound {
+    // A fraction of the larger (in absolute value) of the two numbers.
+    Relative (f64),
+    Absolute (f64),
+}
+
+// Numbers, as matched by the RE (or its first capture group, if it has
+// one), that are compared within a tolerance instead of exactly.
+#[derive(Clone)]
+pub struct NumericTolerance {
+    pub re : Regex,
+    pub bound : ToleranceBound,
+}
+
+impl NumericTolerance {
+    pub fn parse(s : &str) -> Result<NumericTolerance, String> {
+        use self::ToleranceBound::*;
+        let malformed = || format!("Tolerance `{}` is not of the form [RE:]N% or [RE:]abs:N", s);
+        let (prefix, num) = match s.rfind(':') {
+            Some (i) => (Some (&s[..i]), &s[i + 1..]),
+            None => (None, s),
+        };
+        let (re, num, bound) : (Option<&str>, &str, fn(f64) -> ToleranceBound) =
+            if let Some (num) = num.strip_suffix('%') {
+                (prefix, num, |n| Relative (n / 100.0))
+            } else {
+                match prefix.map(|p| (p, p.strip_suffix(":abs"))) {
+                    Some (("abs", _)) => (None, num, Absolute),
+                    Some ((_, Some (re))) => (Some (re), num, Absolute),
+                    _ => return Err (malformed()),
+                }
+            };
+        let bound = match num.parse::<f64>() {
+            Ok (n) if n >= 0.0 => bound(n),
+            _ => return Err (malformed()),
+        };
+        let re = re.unwrap_or(r"-?\b\d+(?:\.\d+)?(?:[eE][-+]?\d+)?\b");
+        let re = match RegexBuilder::new(re).multi_line(true).build() {
+            Ok (re) => re,
+            Err (err) => return Err (format!("Could not compile regular expression `{}`: {}",
+                                             re, err)),
+        };
+        Ok (NumericTolerance {
+            re,
+            bound,
+        })
+    }
+    fn numbers<'a>(&self, key : &'a [u8]) -> Vec<&'a [u8]> {
+        self.re.captures_iter(key).map(|caps| {
+            caps.get(1).unwrap_or_else(|| caps.get(0).unwrap()).as_bytes()
+        }).collect()
+    }
+    // The key, with the numbers removed.
+    pub fn mask<'a>(&self, key : &'a [u8]) -> Cow<'a, [u8]> {
+        let mut ret = vec![];
+        let mut off = 0;
+        for caps in self.re.captures_iter(key) {
+            let m = caps.get(1).unwrap_or_else(|| caps.get(0).unwrap());
+            ret.extend_from_slice(&key[off..m.start()]);
+            off = m.end()
+        }
+        if off == 0 {
+            return Cow::Borrowed (key)
+        }
+        ret.extend_from_slice(&key[off..]);
+        Cow::Owned (ret)
+    }
+    // Whether the numbers of two keys, which are the same when masked,
+    // are all within the tolerance. Anything that doesn't parse as a
+    // number needs to be the same.
+    pub fn within(&self, old_key : &[u8], new_key : &[u8]) -> bool {
+        let parse = |n : &[u8]| {
+            ::std::str::from_utf8(n).ok().and_then(|n| n.parse::<f64>().ok())
+        };
+        let old_nums = self.numbers(old_key);
+        let new_nums = self.numbers(new_key);
+        old_nums.len() == new_nums.len() && old_nums.iter().zip(new_nums).all(|(o, n)| {
+            match (parse(o), parse(n)) {
+                (Some (o), Some (n)) => {
+                    let diff = (o - n).abs();
+                    match self.bound {
+                        ToleranceBound::Relative (r) => diff <= r * o.abs().max(n.abs()),
+                        ToleranceBound::Absolute (a) => diff <= a,
+                    }
+                },
+                _ => o == &n,
+            }
+        })
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum ContextLineFormat {
     CC (CharacterClassExpansion),
@@ -40,18 +332,168 @@ impl ContextLineFormat {
         vec!["cc", "ccwide", "wdiff", "old", "new"]
     }
     pub fn new(s : &str) -> ContextLineFormat {
+        match s.parse() {
+            Ok (format) => format,
+            Err (err) => panic!("{}", err),
+        }
+    }
+}
+
+impl FromStr for ContextLineFormat {
+    type Err = String;
+    fn from_str(s : &str) -> Result<ContextLineFormat, String> {
         use self::ContextLineFormat::*;
         use self::CharacterClassExpansion::*;
-        if s == "cc" {
-            CC (Narrow)
-        } else if s == "ccmin" {
-            CC (Wide)
-        } else if s == "wdiff" {
-            Wdiff
-        } else if s == "old" {
-            Old
-        } else if s == "new" {
-            New
+        match s {
+            "cc" => Ok (CC (Narrow)),
+            "ccwide" => Ok (CC (Wide)),
+            "wdiff" => Ok (Wdiff),
+            "old" => Ok (Old),
+            "new" => Ok (New),
+            _ => Err (format!("Unsupported value: `{}`", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum DiffAlgorithm {
+    Lcs,
+    Myers,
+    Patience,
+    Histogram,
+}
+
+impl DiffAlgorithm {
+    pub fn allowed_values() -> Vec<&'static str> {
+        vec!["myers", "patience", "histogram", "lcs"]
+    }
+    pub fn new(s : &str) -> DiffAlgorithm {
+        use self::DiffAlgorithm::*;
+        if s == "myers" {
+            Myers
+        } else if s == "patience" {
+            Patience
+        } else if s == "histogram" {
+            Histogram
+        } else if s == "lcs" {
+            Lcs
+        } else {
+            panic!("Unsupported value: `{}`", s);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum ColorWhen {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorWhen {
+    pub fn allowed_values() -> Vec<&'static str> {
+        vec!["auto", "always", "never"]
+    }
+    pub fn new(s : &str) -> ColorWhen {
+        use self::ColorWhen::*;
+        if s == "auto" {
+            Auto
+        } else if s == "always" {
+            Always
+        } else if s == "never" {
+            Never
+        } else {
+            panic!("Unsupported value: `{}`", s);
+        }
+    }
+}
+
+// What to do with a line that's matched by more than one of the REs.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MultiMatch {
+    Error,
+    // Use the RE that was given first.
+    First,
+    // Use the RE that selects the most bytes.
+    Longest,
+    // Select whatever any of the REs capture.
+    AllConcat,
+}
+
+impl MultiMatch {
+    pub fn allowed_values() -> Vec<&'static str> {
+        vec!["error", "first", "longest", "all-concat"]
+    }
+    pub fn new(s : &str) -> MultiMatch {
+        use self::MultiMatch::*;
+        if s == "error" {
+            Error
+        } else if s == "first" {
+            First
+        } else if s == "longest" {
+            Longest
+        } else if s == "all-concat" {
+            AllConcat
+        } else {
+            panic!("Unsupported value: `{}`", s);
+        }
+    }
+}
+
+// The engine that compiles the REs given with -r, -i and -I.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RegexEngine {
+    // The regex crate: linear time, but no lookaround or backreferences.
+    Regex,
+    // fancy-regex, which backtracks to support both. Only available
+    // when built with the `fancy` feature.
+    Fancy,
+}
+
+impl RegexEngine {
+    pub fn allowed_values() -> Vec<&'static str> {
+        vec!["regex", "fancy"]
+    }
+    pub fn new(s : &str) -> RegexEngine {
+        use self::RegexEngine::*;
+        if s == "regex" {
+            Regex
+        } else if s == "fancy" {
+            Fancy
+        } else {
+            panic!("Unsupported value: `{}`", s);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Unified,
+    SideBySide,
+    Json,
+    Html,
+    Context,
+    Normal,
+}
+
+impl OutputFormat {
+    pub fn allowed_values() -> Vec<&'static str> {
+        vec!["unified", "side-by-side", "json", "html", "context", "normal"]
+    }
+    pub fn new(s : &str) -> OutputFormat {
+        use self::OutputFormat::*;
+        if s == "unified" {
+            Unified
+        } else if s == "side-by-side" {
+            SideBySide
+        } else if s == "json" {
+            Json
+        } else if s == "html" {
+            Html
+        } else if s == "context" {
+            Context
+        } else if s == "normal" {
+            Normal
         } else {
             panic!("Unsupported value: `{}`", s);
         }
@@ -66,6 +508,31 @@ pub struct Conf {
     pub context_tokenization : ContextLineTokenization,
     pub context_format: ContextLineFormat,
     pub display_selected: bool,
+    pub diff_algorithm : DiffAlgorithm,
+    // Diff in windows of this many lines, instead of reading in
+    // the whole files.
+    pub stream_window : Option<usize>,
+    // Use ANSI escapes to color the output.
+    pub color : bool,
+    pub output_format : OutputFormat,
+    // Total width of the side by side output.
+    pub width : usize,
+    // Lines matched by this RE are section headings, to be displayed
+    // in the headers of the hunks that follow them.
+    pub section_re : Option<Regex>,
+    // Display similar removed and added lines together.
+    pub pair_changes : bool,
+    // User-defined character classes, tried in order before the
+    // predefined ones.
+    pub cc_classes : CcClasses,
+    // Applied in order to the selected part of each line.
+    pub substitutions : Vec<Substitution>,
+    // Values matched by this RE are compared up to a consistent
+    // renaming, instead of as is.
+    pub rename_re : Option<Regex>,
+    pub numeric_tolerance : Option<NumericTolerance>,
+    pub multi_match : MultiMatch,
+    pub regex_engine : RegexEngine,
 }
 
 impl Conf {
@@ -74,9 +541,22 @@ impl Conf {
             debug : false,
             context : 3,
             mark_changed_context : false,
-            context_tokenization : ContextLineTokenization::Word,
+            context_tokenization : ContextLineTokenization::word(),
             context_format : ContextLineFormat::Wdiff,
             display_selected : false,
+            diff_algorithm : DiffAlgorithm::Myers,
+            stream_window : None,
+            color : false,
+            output_format : OutputFormat::Unified,
+            width : 130,
+            section_re : None,
+            pair_changes : false,
+            cc_classes : CcClasses::new(vec![]),
+            substitutions : vec![],
+            rename_re : None,
+            numeric_tolerance : None,
+            multi_match : MultiMatch::Error,
+            regex_engine : RegexEngine::Regex,
         }
     }
 }
@@ -0,0 +1,164 @@
+use std::io;
+use std::io::prelude::*;
+use std::cmp;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use conf::{Conf, OutputFormat};
+use html;
+use json;
+
+// Sorted names of the entries of a directory. We sort by bytes, which
+// is what diff does in the C locale.
+fn dir_entries(dir : &Path) -> io::Result<Vec<OsString>> {
+    let mut names = vec![];
+    for entry in fs::read_dir(dir)? {
+        names.push(entry?.file_name())
+    }
+    names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    Ok (names)
+}
+
+fn only_in(out : &mut Write, conf : &Conf, dir : &Path, name : &OsString) -> io::Result<()> {
+    match conf.output_format {
+        OutputFormat::Json => return json::write_only_in(out, dir, name.as_bytes()),
+        OutputFormat::Html => return html::write_only_in(out, dir, name.as_bytes()),
+        _ => (),
+    }
+    let mut acc = vec![];
+    acc.write_all(b"Only in ")?;
+    acc.write_all(dir.as_os_str().as_bytes())?;
+    acc.write_all(b": ")?;
+    acc.write_all(name.as_bytes())?;
+    acc.write_all(b"\n")?;
+    out.write_all(&acc)
+}
+
+fn kind_mismatch(out : &mut Write, conf : &Conf, old : &Path, new : &Path,
+                 old_is_dir : bool) -> io::Result<()> {
+    let (old_kind, new_kind) = if old_is_dir {
+        (&b"directory"[..], &b"regular file"[..])
+    } else {
+        (&b"regular file"[..], &b"directory"[..])
+    };
+    match conf.output_format {
+        OutputFormat::Json => return json::write_kind_mismatch(out, old, new, old_kind, new_kind),
+        OutputFormat::Html => return html::write_kind_mismatch(out, old, new, old_kind, new_kind),
+        _ => (),
+    }
+    let mut acc = vec![];
+    acc.write_all(b"File ")?;
+    acc.write_all(old.as_os_str().as_bytes())?;
+    acc.write_all(b" is a ")?;
+    acc.write_all(old_kind)?;
+    acc.write_all(b" while file ")?;
+    acc.write_all(new.as_os_str().as_bytes())?;
+    acc.write_all(b" is a ")?;
+    acc.write_all(new_kind)?;
+    acc.write_all(b"\n")?;
+    out.write_all(&acc)
+}
+
+// Compare a pair of files found while walking the directories. Errors
+// only affect the exit status; like diff -r, we keep going so that the
+// user gets to see all the differences we were able to find.
+fn diff_entry(out : &mut Write, conf : &Conf,
+              diff_pair : &mut FnMut(&mut Write, &Path, &Path) -> io::Result<i32>,
+              ancestors : &mut Vec<(DirId, DirId)>,
+              old : &Path, new : &Path) -> io::Result<i32> {
+    let old_is_dir = old.is_dir();
+    let new_is_dir = new.is_dir();
+    let res = match (old_is_dir, new_is_dir) {
+        (true, true) => walk(out, conf, diff_pair, ancestors, old, new),
+        (false, false) => diff_pair(out, old, new),
+        _ => {
+            kind_mismatch(out, conf, old, new, old_is_dir)?;
+            Ok (1)
+        },
+    };
+    match res {
+        Ok (ecode) => Ok (ecode),
+        Err (err) => {
+            eprintln!("Error comparing {} and {}: {}",
+                      old.display(), new.display(), err);
+            Ok (2)
+        },
+    }
+}
+
+/// Walk two directory trees in parallel, calling `diff_pair` for every
+/// pair of files with the same relative path. Entries that only exist
+/// on one side are reported in the format used by `diff -r`. The
+/// returned exit code is the maximum of the exit codes of the
+/// individual comparisons.
+pub fn diff_dirs(out : &mut Write, conf : &Conf,
+                 diff_pair : &mut FnMut(&mut Write, &Path, &Path) -> io::Result<i32>,
+                 old : &Path, new : &Path) -> io::Result<i32> {
+    walk(out, conf, diff_pair, &mut vec![], old, new)
+}
+
+// A directory, as identified by its device and inode numbers.
+type DirId = (u64, u64);
+
+fn dir_id(dir : &Path) -> io::Result<DirId> {
+    let meta = fs::metadata(dir)?;
+    Ok ((meta.dev(), meta.ino()))
+}
+
+// Symbolic links can lead back to a directory we're already in (think
+// `loop -> ..`), so, like diff, we keep track of the directories on
+// the way down and refuse to go into one of them again.
+fn walk(out : &mut Write, conf : &Conf,
+        diff_pair : &mut FnMut(&mut Write, &Path, &Path) -> io::Result<i32>,
+        ancestors : &mut Vec<(DirId, DirId)>,
+        old : &Path, new : &Path) -> io::Result<i32> {
+    let ids = (dir_id(old)?, dir_id(new)?);
+    if ancestors.iter().any(|&(o, n)| o == ids.0 || n == ids.1) {
+        eprintln!("Error comparing {} and {}: recursive directory loop",
+                  old.display(), new.display());
+        return Ok (2)
+    }
+    ancestors.push(ids);
+    let res = walk_entries(out, conf, diff_pair, ancestors, old, new);
+    ancestors.pop();
+    res
+}
+
+fn walk_entries(out : &mut Write, conf : &Conf,
+                diff_pair : &mut FnMut(&mut Write, &Path, &Path) -> io::Result<i32>,
+                ancestors : &mut Vec<(DirId, DirId)>,
+                old : &Path, new : &Path) -> io::Result<i32> {
+    let old_names = dir_entries(old)?;
+    let new_names = dir_entries(new)?;
+    let mut ecode = 0;
+    let mut old_it = old_names.iter().peekable();
+    let mut new_it = new_names.iter().peekable();
+    loop {
+        let ord = match (old_it.peek(), new_it.peek()) {
+            (None, None) => break,
+            (Some (_), None) => cmp::Ordering::Less,
+            (None, Some (_)) => cmp::Ordering::Greater,
+            (Some (o), Some (n)) => o.as_bytes().cmp(n.as_bytes()),
+        };
+        match ord {
+            cmp::Ordering::Less => {
+                only_in(out, conf, old, old_it.next().unwrap())?;
+                ecode = cmp::max(ecode, 1);
+            },
+            cmp::Ordering::Greater => {
+                only_in(out, conf, new, new_it.next().unwrap())?;
+                ecode = cmp::max(ecode, 1);
+            },
+            cmp::Ordering::Equal => {
+                let name = old_it.next().unwrap();
+                new_it.next();
+                let res = diff_entry(out, conf, diff_pair, ancestors,
+                                     &old.join(name), &new.join(name))?;
+                ecode = cmp::max(ecode, res);
+            },
+        }
+    }
+    Ok (ecode)
+}
@@ -0,0 +1,149 @@
+// The REs that select and ignore parts of lines (-r, -i and -I), as
+// compiled by one of the two engines. The regex crate is the default;
+// fancy-regex backtracks, which allows for lookaround and backreferences
+// at the cost of matching in exponential time in the worst case. An RE
+// can pick its own engine by starting with `(?regex)` or `(?fancy)`,
+// which are not valid syntax for either engine, so no RE that worked
+// before has its meaning changed.
+//
+// fancy-regex only matches str, so lines that aren't valid UTF-8 are
+// never matched by a fancy RE.
+use std::ops::Range;
+use regex::bytes::{Regex, RegexBuilder};
+#[cfg(feature = "fancy")]
+use std::process::exit;
+#[cfg(feature = "fancy")]
+use std::str;
+#[cfg(feature = "fancy")]
+use fancy_regex;
+use conf::RegexEngine;
+
+enum Compiled {
+    Regex (Regex),
+    #[cfg(feature = "fancy")]
+    Fancy (fancy_regex::Regex),
+}
+
+pub struct Re {
+    // As given by the user, for error messages.
+    src : String,
+    compiled : Compiled,
+}
+
+// The engine an RE asks for, if any, and the RE itself.
+fn split_engine(s : &str) -> (Option<RegexEngine>, &str) {
+    if let Some (re) = s.strip_prefix("(?regex)") {
+        (Some (RegexEngine::Regex), re)
+    } else if let Some (re) = s.strip_prefix("(?fancy)") {
+        (Some (RegexEngine::Fancy), re)
+    } else {
+        (None, s)
+    }
+}
+
+#[cfg(feature = "fancy")]
+fn compile_fancy(s : &str) -> Result<Compiled, String> {
+    // Our lines contain the EOL character, hence the multi-line mode
+    // (same as for the regex crate).
+    fancy_regex::Regex::new(&format!("(?m){}", s))
+        .map(Compiled::Fancy).map_err(|err| err.to_string())
+}
+
+#[cfg(not(feature = "fancy"))]
+fn compile_fancy(_ : &str) -> Result<Compiled, String> {
+    Err ("the fancy regex engine is not available (subdiff was built without the `fancy` feature)"
+         .to_string())
+}
+
+// Matching can fail, e.g. when an RE backtracks too much. There's no
+// sensible way to go on comparing the files at that point.
+#[cfg(feature = "fancy")]
+fn match_failed(src : &str, err : fancy_regex::Error) -> ! {
+    eprintln!("Could not match regular expression `{}`: {}", src, err);
+    exit(2)
+}
+
+impl Re {
+    /// Compile s using the given engine, unless it asks for another one.
+    pub fn build(engine : RegexEngine, s : &str) -> Result<Re, String> {
+        let (requested, re) = split_engine(s);
+        let compiled = match requested.unwrap_or(engine) {
+            RegexEngine::Regex => {
+                RegexBuilder::new(re).multi_line(true).build()
+                    .map(Compiled::Regex).map_err(|err| err.to_string())?
+            },
+            RegexEngine::Fancy => compile_fancy(re)?,
+        };
+        Ok (Re {
+            src : s.to_string(),
+            compiled,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.src
+    }
+
+    /// The RE as compiled by the regex crate, if that's the engine it uses.
+    pub fn plain(&self) -> Option<&Regex> {
+        match self.compiled {
+            Compiled::Regex (ref re) => Some (re),
+            #[cfg(feature = "fancy")]
+            Compiled::Fancy (_) => None,
+        }
+    }
+
+    /// Includes the implicit group of the whole match.
+    pub fn captures_len(&self) -> usize {
+        match self.compiled {
+            Compiled::Regex (ref re) => re.captures_len(),
+            #[cfg(feature = "fancy")]
+            Compiled::Fancy (ref re) => re.captures_len(),
+        }
+    }
+
+    pub fn is_match(&self, line : &[u8]) -> bool {
+        match self.compiled {
+            Compiled::Regex (ref re) => re.is_match(line),
+            #[cfg(feature = "fancy")]
+            Compiled::Fancy (ref re) => match str::from_utf8(line) {
+                Ok (line) => re.is_match(line).unwrap_or_else(|err| match_failed(&self.src, err)),
+                Err (_) => false,
+            },
+        }
+    }
+
+    /// The ranges of the capture groups of the first match, starting
+    /// with that of the whole match. None for groups that didn't
+    /// participate in the match.
+    pub fn captures(&self, line : &[u8]) -> Option<Vec<Option<Range<usize>>>> {
+        match self.compiled {
+            Compiled::Regex (ref re) => re.captures(line).map(|caps| {
+                (0..caps.len()).map(|i| caps.get(i).map(|m| m.start()..m.end())).collect()
+            }),
+            #[cfg(feature = "fancy")]
+            Compiled::Fancy (ref re) => {
+                let line = str::from_utf8(line).ok()?;
+                let caps = re.captures(line).unwrap_or_else(|err| match_failed(&self.src, err));
+                caps.map(|caps| {
+                    (0..caps.len()).map(|i| caps.get(i).map(|m| m.start()..m.end())).collect()
+                })
+            },
+        }
+    }
+
+    /// The ranges of all the non-overlapping matches in the line.
+    pub fn find_ranges(&self, line : &[u8]) -> Vec<Range<usize>> {
+        match self.compiled {
+            Compiled::Regex (ref re) => re.find_iter(line).map(|m| m.start()..m.end()).collect(),
+            #[cfg(feature = "fancy")]
+            Compiled::Fancy (ref re) => match str::from_utf8(line) {
+                Ok (line) => re.find_iter(line).map(|m| {
+                    let m = m.unwrap_or_else(|err| match_failed(&self.src, err));
+                    m.start()..m.end()
+                }).collect(),
+                Err (_) => vec![],
+            },
+        }
+    }
+}
@@ -0,0 +1,208 @@
+// Histogram diff, along the lines of the implementation in JGit: split
+// the sequences around the longest common region that contains the
+// rarest elements (counting occurrences in the old sequence), then
+// do the same on either side. Elements that are very common (think
+// blank lines or lone braces) are never used to anchor the split, which
+// falls back to Myers when there's nothing better to go on.
+//
+// The occurrences are indexed once, for the whole of the old sequence,
+// and the ranges left to diff are kept on an explicit stack, so that
+// there's no recursion depth to worry about. The scanning for regions
+// is bounded, with whatever is left past that going to Myers.
+use std::cmp;
+use std::collections::HashMap;
+use std::hash::Hash;
+use super::lcs_diff::DiffResult;
+use myers;
+use myers::Edit;
+
+// Elements that appear more often than this in the old range being
+// diffed are not considered for anchoring the diff.
+const MAX_CHAIN_LEN : usize = 64;
+
+// Upper bound on the work spent looking for regions, as a multiple of
+// the combined length of the two sequences.
+const WORK_FACTOR : usize = 64;
+
+pub fn diff<T : Eq + Hash + Clone>(old : &[T], new : &[T]) -> Vec<DiffResult<T>> {
+    let (edits, _) = edits(old, new);
+    myers::edits_to_results(old, new, &edits)
+}
+
+/// The edits for turning `old` into `new`, along with the work (in
+/// elements looked at) that went into finding the anchoring regions.
+pub fn edits<T : Eq + Hash>(old : &[T], new : &[T]) -> (Vec<Edit>, usize) {
+    let mut histogram = Histogram::new(old, new);
+    let mut edits = vec![];
+    histogram.diff(&mut edits);
+    (edits, histogram.work)
+}
+
+// What's left to do, in order, when popped off the stack.
+enum Task {
+    // Diff the two ranges, in old and new respectively.
+    Diff (usize, usize, usize, usize),
+    // Lines already known to be common, at the given offsets in old
+    // and new, for the given length.
+    Common (usize, usize, usize),
+}
+
+struct Histogram<'a, T : 'a> {
+    old : &'a [T],
+    new : &'a [T],
+    // The offsets of each element in old, in increasing order.
+    occurrences : HashMap<&'a T, Vec<usize>>,
+    work : usize,
+    budget : usize,
+}
+
+impl<'a, T : Eq + Hash> Histogram<'a, T> {
+    fn new(old : &'a [T], new : &'a [T]) -> Histogram<'a, T> {
+        let mut occurrences : HashMap<&T, Vec<usize>> = HashMap::new();
+        for (i, el) in old.iter().enumerate() {
+            occurrences.entry(el).or_default().push(i)
+        }
+        Histogram {
+            old,
+            new,
+            occurrences,
+            work : 0,
+            budget : WORK_FACTOR * (old.len() + new.len()),
+        }
+    }
+
+    fn diff(&mut self, edits : &mut Vec<Edit>) {
+        let mut tasks = vec![Task::Diff (0, self.old.len(), 0, self.new.len())];
+        while let Some (task) = tasks.pop() {
+            match task {
+                Task::Common (o, n, len) => push_common(o, n, len, edits),
+                Task::Diff (olo, ohi, nlo, nhi) => {
+                    self.diff_range(olo, ohi, nlo, nhi, &mut tasks, edits)
+                },
+            }
+        }
+    }
+
+    // Diff the given ranges, pushing whatever can't be dealt with right
+    // away to tasks, last thing first.
+    fn diff_range(&mut self, olo : usize, ohi : usize, nlo : usize, nhi : usize,
+                  tasks : &mut Vec<Task>, edits : &mut Vec<Edit>) {
+        let (old, new) = (&self.old[olo..ohi], &self.new[nlo..nhi]);
+        let prefix = old.iter().zip(new).take_while(|&(o, n)| o == n).count();
+        push_common(olo, nlo, prefix, edits);
+        let (olo, nlo) = (olo + prefix, nlo + prefix);
+        let (old, new) = (&old[prefix..], &new[prefix..]);
+        let suffix = old.iter().rev().zip(new.iter().rev())
+            .take_while(|&(o, n)| o == n).count();
+        let (ohi, nhi) = (ohi - suffix, nhi - suffix);
+        tasks.push(Task::Common (ohi, nhi, suffix));
+
+        let region = if self.work < self.budget {
+            self.rarest_region(olo, ohi, nlo, nhi)
+        } else {
+            None
+        };
+        match region {
+            None => {
+                myers::diff_range(&self.old[olo..ohi], &self.new[nlo..nhi],
+                                  olo, nlo, edits)
+            },
+            Some ((o, n, len)) => {
+                tasks.push(Task::Diff (o + len, ohi, n + len, nhi));
+                tasks.push(Task::Common (o, n, len));
+                tasks.push(Task::Diff (olo, o, nlo, n));
+            },
+        }
+    }
+
+    // The offsets in old of the occurrences of el within [lo, hi).
+    fn occurrences_in(&self, el : &T, lo : usize, hi : usize) -> &[usize] {
+        match self.occurrences.get(el) {
+            Some (positions) => {
+                // The offsets are distinct, so a search for either end
+                // of the range lands where that range starts or ends.
+                let start = match positions.binary_search(&lo) {
+                    Ok (i) | Err (i) => i,
+                };
+                let end = match positions.binary_search(&hi) {
+                    Ok (i) | Err (i) => i,
+                };
+                &positions[start..end]
+            },
+            None => &[],
+        }
+    }
+
+    // Find the common region whose rarest element has the lowest number
+    // of occurrences in the old range, preferring longer regions on ties
+    // and, failing that, the one closest to the middle of the new range,
+    // which keeps the splits balanced. Returns the offsets of the region
+    // in old and new and its length.
+    fn rarest_region(&mut self, olo : usize, ohi : usize, nlo : usize, nhi : usize)
+                     -> Option<(usize, usize, usize)> {
+        let (old, new) = (self.old, self.new);
+        let mut work = 0;
+        let mut best : Option<(usize, usize, usize)> = None;
+        let mut best_count = MAX_CHAIN_LEN;
+        // Twice the distance of a region's middle from that of the new range.
+        let skew = |sn : usize, len : usize| {
+            let (a, b) = (2 * sn + len, nlo + nhi);
+            cmp::max(a, b) - cmp::min(a, b)
+        };
+        let mut j = nlo;
+        while j < nhi {
+            work += 1;
+            let mut next_j = j + 1;
+            let positions = self.occurrences_in(&new[j], olo, ohi);
+            if positions.is_empty() || positions.len() > best_count {
+                j = next_j;
+                continue
+            }
+            for &i in positions {
+                // Extend the match in both directions, keeping track of
+                // the rarest element in it.
+                let mut region_count = positions.len();
+                let (mut so, mut sn) = (i, j);
+                while so > olo && sn > nlo && old[so - 1] == new[sn - 1] {
+                    so -= 1;
+                    sn -= 1;
+                    region_count = cmp::min(region_count,
+                                            self.occurrences_in(&old[so], olo, ohi).len());
+                }
+                let (mut eo, mut en) = (i + 1, j + 1);
+                while eo < ohi && en < nhi && old[eo] == new[en] {
+                    region_count = cmp::min(region_count,
+                                            self.occurrences_in(&old[eo], olo, ohi).len());
+                    eo += 1;
+                    en += 1;
+                }
+                let len = eo - so;
+                work += len;
+                let better = match best {
+                    None => true,
+                    Some ((_, best_sn, best_len)) => {
+                        region_count < best_count
+                            || (region_count == best_count && len > best_len)
+                            || (region_count == best_count && len == best_len
+                                && skew(sn, len) < skew(best_sn, best_len))
+                    },
+                };
+                if better {
+                    best = Some ((so, sn, len));
+                    best_count = region_count;
+                }
+                // No point in looking for regions starting inside this one.
+                next_j = cmp::max(next_j, en);
+            }
+            j = next_j;
+        }
+        self.work += work;
+        best
+    }
+}
+
+fn push_common(o : usize, n : usize, len : usize, edits : &mut Vec<Edit>) {
+    for i in 0..len {
+        edits.push(Edit::Common (o + i, n + i))
+    }
+}
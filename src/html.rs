@@ -0,0 +1,394 @@
+// A self-contained HTML report. The whole run ends up in a single page,
+// with a section per pair of files and a collapsible block per hunk.
+// Every hunk is rendered both inline and side by side; a checkbox at the
+// top of the page switches between the two views, without any scripts.
+//
+// The point of the report is to make it easy to see why lines did or
+// did not compare equal, so each line highlights the parts that were
+// selected by the REs and the parts that the ignore RE dropped.
+use std::io;
+use std::io::prelude::*;
+use std::cmp;
+use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use super::lcs_diff;
+use super::lcs_diff::{DiffResult, DiffElement};
+use super::Selection;
+use conf::{Conf, ContextLineTokenization};
+use hunked::{Hunk, Side, write_off_len};
+use wdiff::{Writeable, tokenize, tokenize_re};
+
+// Per-byte flags, which turn into the classes of the spans the line is
+// rendered as.
+const SELECTED : u8 = 1;
+const UNSELECTED : u8 = 2;
+const IGNORED : u8 = 4;
+const CHANGED : u8 = 8;
+
+const CLASSES : [(u8, &str); 4] = [
+    (SELECTED, "sel"),
+    (UNSELECTED, "uns"),
+    (IGNORED, "ign"),
+    (CHANGED, "chg"),
+];
+
+const STYLE : &str = "\
+body { font-family: sans-serif; margin: 1em 2em; }
+h1 { font-size: 1.3em; }
+h2 { font-size: 1.1em; font-family: monospace; }
+summary { font-family: monospace; color: #066; cursor: pointer; }
+table { border-collapse: collapse; width: 100%; font-family: monospace; margin: 0.3em 0 1em; }
+td { white-space: pre-wrap; word-break: break-all; vertical-align: top; padding: 0 0.4em; }
+td.ln { color: #888; text-align: right; width: 1%; white-space: pre; user-select: none; }
+td.mk { width: 1%; user-select: none; }
+.del { background: #fee; }
+.add { background: #efe; }
+.ctx.old { background: #fafafa; }
+.ctx.new { background: #fafafa; }
+.nil { background: #f4f4f4; }
+.sel { background: #ffd; outline: 1px dotted #cc8; }
+.uns { color: #999; }
+.ign { color: #999; text-decoration: line-through; }
+.old .chg, .del .chg { background: #fbb; }
+.new .chg, .add .chg { background: #bfb; }
+.legend span { margin-right: 1.5em; font-family: monospace; }
+.only { font-family: monospace; }
+#sbs:checked ~ main table.inline { display: none; }
+#sbs:not(:checked) ~ main table.sbs { display: none; }
+";
+
+fn escape(buf : &mut Vec<u8>, s : &[u8]) {
+    for ch in String::from_utf8_lossy(s).chars() {
+        match ch {
+            '&' => buf.extend_from_slice(b"&amp;"),
+            '<' => buf.extend_from_slice(b"&lt;"),
+            '>' => buf.extend_from_slice(b"&gt;"),
+            '"' => buf.extend_from_slice(b"&quot;"),
+            ch => {
+                let mut utf8 = [0; 4];
+                buf.extend_from_slice(ch.encode_utf8(&mut utf8).as_bytes())
+            },
+        }
+    }
+}
+
+fn path(buf : &mut Vec<u8>, p : &Path) {
+    escape(buf, p.as_os_str().as_bytes())
+}
+
+/// Start the page. Everything else goes in between this and `end`.
+pub fn begin(out : &mut Write, old : &Path, new : &Path) -> io::Result<()> {
+    let mut buf = vec![];
+    buf.extend_from_slice(b"<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>");
+    path(&mut buf, old);
+    buf.extend_from_slice(b" vs ");
+    path(&mut buf, new);
+    buf.extend_from_slice(b"</title>\n<style>\n");
+    buf.extend_from_slice(STYLE.as_bytes());
+    buf.extend_from_slice(b"</style>\n</head>\n<body>\n<h1>");
+    path(&mut buf, old);
+    buf.extend_from_slice(b" vs ");
+    path(&mut buf, new);
+    buf.extend_from_slice(b"</h1>\n\
+<p class=\"legend\"><span class=\"sel\">selected by -r</span>\
+<span class=\"uns\">not selected</span>\
+<span class=\"ign\">removed by -i</span></p>\n\
+<input type=\"checkbox\" id=\"sbs\"><label for=\"sbs\">Side by side</label>\n\
+<main>\n");
+    out.write_all(&buf)
+}
+
+pub fn end(out : &mut Write) -> io::Result<()> {
+    out.write_all(b"</main>\n</body>\n</html>\n")
+}
+
+/// Start the section of a pair of files that differ.
+pub fn file_begin(out : &mut Write, old : &Path, new : &Path) -> io::Result<()> {
+    let mut buf = vec![];
+    buf.extend_from_slice(b"<section>\n<h2>--- ");
+    path(&mut buf, old);
+    buf.extend_from_slice(b"<br>+++ ");
+    path(&mut buf, new);
+    buf.extend_from_slice(b"</h2>\n");
+    out.write_all(&buf)
+}
+
+pub fn file_end(out : &mut Write) -> io::Result<()> {
+    out.write_all(b"</section>\n")
+}
+
+/// The HTML counterpart of the "Only in" lines of `diff -r`.
+pub fn write_only_in(out : &mut Write, dir : &Path, name : &[u8]) -> io::Result<()> {
+    let mut buf = vec![];
+    buf.extend_from_slice(b"<p class=\"only\">Only in ");
+    path(&mut buf, dir);
+    buf.extend_from_slice(b": ");
+    escape(&mut buf, name);
+    buf.extend_from_slice(b"</p>\n");
+    out.write_all(&buf)
+}
+
+/// Reported when one of the paths is a directory and the other is not.
+pub fn write_kind_mismatch(out : &mut Write, old : &Path, new : &Path,
+                           old_kind : &[u8], new_kind : &[u8]) -> io::Result<()> {
+    let mut buf = vec![];
+    buf.extend_from_slice(b"<p class=\"only\">File ");
+    path(&mut buf, old);
+    buf.extend_from_slice(b" is a ");
+    escape(&mut buf, old_kind);
+    buf.extend_from_slice(b" while file ");
+    path(&mut buf, new);
+    buf.extend_from_slice(b" is a ");
+    escape(&mut buf, new_kind);
+    buf.extend_from_slice(b"</p>\n");
+    out.write_all(&buf)
+}
+
+// Flag the bytes of the line according to the part they played in
+// picking its key. When displaying the selected parts, the line is the
+// key, so there's nothing to flag.
+fn line_flags(conf : &Conf, sel : &Selection, line : &[u8]) -> Vec<u8> {
+    let mut flags = vec![0; line.len()];
+    if conf.display_selected {
+        return flags
+    }
+    let spans = sel.spans(conf, line);
+    if let Some (selected) = spans.selected {
+        for f in flags.iter_mut() {
+            *f = UNSELECTED
+        }
+        for r in selected {
+            for f in &mut flags[r] {
+                *f = SELECTED
+            }
+        }
+    }
+    for r in spans.ignored {
+        for f in &mut flags[r] {
+            *f = IGNORED
+        }
+    }
+    flags
+}
+
+fn flag_range(flags : &mut [u8], start : usize, len : usize) {
+    let end = cmp::min(start + len, flags.len());
+    for f in &mut flags[cmp::min(start, end)..end] {
+        *f |= CHANGED
+    }
+}
+
+// Flag the tokens that differ between the two versions of a context line.
+fn flag_changes<T>(old : &[T], new : &[T], old_flags : &mut [u8], new_flags : &mut [u8])
+where T : PartialEq + Clone + Writeable
+{
+    let mut o = 0;
+    let mut n = 0;
+    let mut buf = vec![];
+    for d in lcs_diff::diff(old, new) {
+        buf.clear();
+        match d {
+            DiffResult::Common (el) => {
+                el.data.write_to(&mut buf).unwrap();
+                o += buf.len();
+                n += buf.len()
+            },
+            DiffResult::Removed (el) => {
+                el.data.write_to(&mut buf).unwrap();
+                flag_range(old_flags, o, buf.len());
+                o += buf.len()
+            },
+            DiffResult::Added (el) => {
+                el.data.write_to(&mut buf).unwrap();
+                flag_range(new_flags, n, buf.len());
+                n += buf.len()
+            },
+        }
+    }
+}
+
+// Continuation bytes of UTF-8 sequences stay in the same span as the
+// byte that started the sequence.
+fn is_continuation(b : u8) -> bool {
+    b & 0xc0 == 0x80
+}
+
+// Render the line (w/o its EOL) as a run of spans.
+fn text(buf : &mut Vec<u8>, line : &[u8], flags : &[u8]) {
+    let len = match line.last() {
+        Some (b'\n') => line.len() - 1,
+        _ => line.len(),
+    };
+    let mut i = 0;
+    while i < len {
+        let f = flags[i];
+        let mut j = i + 1;
+        while j < len && (flags[j] == f || is_continuation(line[j])) {
+            j += 1
+        }
+        if f == 0 {
+            escape(buf, &line[i..j])
+        } else {
+            let classes : Vec<&str> = CLASSES.iter()
+                .filter(|&&(flag, _)| f & flag != 0)
+                .map(|&(_, class)| class).collect();
+            write!(buf, "<span class=\"{}\">", classes.join(" ")).unwrap();
+            escape(buf, &line[i..j]);
+            buf.extend_from_slice(b"</span>")
+        }
+        i = j
+    }
+}
+
+fn lineno(buf : &mut Vec<u8>, off : Option<usize>) {
+    buf.extend_from_slice(b"<td class=\"ln\">");
+    if let Some (off) = off {
+        write!(buf, "{}", off + 1).unwrap()
+    }
+    buf.extend_from_slice(b"</td>");
+}
+
+// A line of one of the two files, ready to be rendered.
+struct Line<'a> {
+    off : usize,
+    text : &'a [u8],
+    flags : Vec<u8>,
+}
+
+fn inline_row(buf : &mut Vec<u8>, class : &str, marker : &str,
+              o : Option<usize>, n : Option<usize>, line : &Line) {
+    write!(buf, "<tr class=\"{}\">", class).unwrap();
+    lineno(buf, o);
+    lineno(buf, n);
+    write!(buf, "<td class=\"mk\">{}</td><td>", marker).unwrap();
+    text(buf, line.text, &line.flags);
+    buf.extend_from_slice(b"</td></tr>\n");
+}
+
+fn sbs_cell(buf : &mut Vec<u8>, class : &str, line : Option<&Line>) {
+    match line {
+        None => {
+            lineno(buf, None);
+            buf.extend_from_slice(b"<td class=\"nil\"></td>")
+        },
+        Some (line) => {
+            lineno(buf, Some (line.off));
+            write!(buf, "<td class=\"{}\">", class).unwrap();
+            text(buf, line.text, &line.flags);
+            buf.extend_from_slice(b"</td>")
+        },
+    }
+}
+
+// The two renderings of a hunk, built up in parallel.
+struct Tables {
+    inline : Vec<u8>,
+    sbs : Vec<u8>,
+}
+
+impl Tables {
+    fn common(&mut self, conf : &Conf, old : &Line, new : &Line) {
+        if old.text == new.text {
+            inline_row(&mut self.inline, "ctx", " ", Some (old.off), Some (new.off), old)
+        } else {
+            let marker = if conf.mark_changed_context {
+                "!"
+            } else {
+                " "
+            };
+            inline_row(&mut self.inline, "ctx old", marker, Some (old.off), None, old);
+            inline_row(&mut self.inline, "ctx new", marker, None, Some (new.off), new)
+        }
+        self.sbs.extend_from_slice(b"<tr>");
+        sbs_cell(&mut self.sbs, "ctx old", Some (old));
+        sbs_cell(&mut self.sbs, "ctx new", Some (new));
+        self.sbs.extend_from_slice(b"</tr>\n");
+    }
+
+    // Removals come before additions inline, and are paired with them
+    // side by side.
+    fn changes(&mut self, removed : &mut Vec<Line>, added : &mut Vec<Line>) {
+        for line in removed.iter() {
+            inline_row(&mut self.inline, "del", "-", Some (line.off), None, line)
+        }
+        for line in added.iter() {
+            inline_row(&mut self.inline, "add", "+", None, Some (line.off), line)
+        }
+        for i in 0..cmp::max(removed.len(), added.len()) {
+            self.sbs.extend_from_slice(b"<tr>");
+            sbs_cell(&mut self.sbs, "del", removed.get(i));
+            sbs_cell(&mut self.sbs, "add", added.get(i));
+            self.sbs.extend_from_slice(b"</tr>\n");
+        }
+        removed.clear();
+        added.clear();
+    }
+}
+
+/// Write out a hunk as a collapsible block, holding both views of it.
+pub fn write_hunk<L, K>(out : &mut Write, conf : &Conf, sel : &Selection, hunk : &Hunk<L>,
+                        old : &Side<L, K>, new : &Side<L, K>) -> io::Result<()>
+where L : AsRef<[u8]> + PartialEq + Clone, K : AsRef<[u8]>
+{
+    let mut tables = Tables {
+        inline : vec![],
+        sbs : vec![],
+    };
+    let old_line = |o : usize| {
+        let text = old.line(o);
+        Line { off : o, text, flags : line_flags(conf, sel, text) }
+    };
+    let new_line = |n : usize| {
+        let text = new.line(n);
+        Line { off : n, text, flags : line_flags(conf, sel, text) }
+    };
+    let mut removed = vec![];
+    let mut added = vec![];
+    for d in &hunk.items {
+        match d {
+            DiffResult::Common (DiffElement { old_index : Some (o), new_index : Some (n), ..}) => {
+                tables.changes(&mut removed, &mut added);
+                let mut line_o = old_line(*o);
+                let mut line_n = new_line(*n);
+                if line_o.text != line_n.text {
+                    match conf.context_tokenization {
+                        ContextLineTokenization::Char => {
+                            flag_changes(line_o.text, line_n.text,
+                                         &mut line_o.flags, &mut line_n.flags)
+                        },
+                        ContextLineTokenization::Word (ref wb) => {
+                            flag_changes(&tokenize(wb, line_o.text), &tokenize(wb, line_n.text),
+                                         &mut line_o.flags, &mut line_n.flags)
+                        },
+                        ContextLineTokenization::Re (ref re) => {
+                            flag_changes(&tokenize_re(re, line_o.text),
+                                         &tokenize_re(re, line_n.text),
+                                         &mut line_o.flags, &mut line_n.flags)
+                        },
+                    }
+                }
+                tables.common(conf, &line_o, &line_n)
+            },
+            DiffResult::Removed (DiffElement { old_index : Some (o), ..}) => {
+                removed.push(old_line(*o))
+            },
+            DiffResult::Added (DiffElement { new_index : Some (n), ..}) => {
+                added.push(new_line(*n))
+            },
+            _ => panic!("Can't print DiffElement with neither side"),
+        }
+    }
+    tables.changes(&mut removed, &mut added);
+
+    let mut buf = vec![];
+    buf.extend_from_slice(b"<details open>\n<summary>@@ -");
+    write_off_len(&mut buf, hunk.old_start, hunk.old_len)?;
+    buf.extend_from_slice(b" +");
+    write_off_len(&mut buf, hunk.new_start, hunk.new_len)?;
+    buf.extend_from_slice(b" @@</summary>\n<table class=\"inline\">\n");
+    buf.extend(tables.inline);
+    buf.extend_from_slice(b"</table>\n<table class=\"sbs\">\n");
+    buf.extend(tables.sbs);
+    buf.extend_from_slice(b"</table>\n</details>\n");
+    out.write_all(&buf)
+}
@@ -3,12 +3,45 @@ use std::io::prelude::*;
 use std::fmt::Debug;
 use std::collections::VecDeque;
 use std::usize;
+use std::cmp;
+use std::mem;
+use std::iter::Peekable;
+use std::path::Path;
 use super::lcs_diff;
 use super::lcs_diff::{DiffResult, DiffElement};
-use super::conf::{Conf, ContextLineFormat, ContextLineTokenization};
+use super::conf::{Conf, ContextLineFormat, ContextLineTokenization, OutputFormat};
 use super::wdiff::*;
+use color;
+use sidebyside;
 use wdiff::Word;
 
+/// One of the two files, as far as the hunks we're writing out are
+/// concerned. `lines[0]` and `keys[0]` are at line offset `base` of
+/// the file.
+pub struct Side<'a, L : 'a, K : 'a> {
+    pub path : &'a Path,
+    pub lines : &'a [L],
+    pub keys : &'a [K],
+    pub base : usize,
+}
+
+impl<'a, L : AsRef<[u8]>, K : AsRef<[u8]>> Side<'a, L, K> {
+    pub fn new(path : &'a Path, lines : &'a [L], keys : &'a [K]) -> Side<'a, L, K> {
+        Side {
+            path,
+            lines,
+            keys,
+            base : 0,
+        }
+    }
+    pub fn line(&self, off : usize) -> &[u8] {
+        self.lines[off - self.base].as_ref()
+    }
+    pub fn key(&self, off : usize) -> &[u8] {
+        self.keys[off - self.base].as_ref()
+    }
+}
+
 pub trait DisplayableHunk where Self::DiffItem : PartialEq + Clone + Debug + Sized {
     type DiffItem;
     fn do_write(&self, &Conf,
@@ -77,11 +110,17 @@ fn update_indices<T : PartialEq + Clone>(d : &mut DiffResult<T>, offsets : &File
 // the info we need in order to display the hunk header and related lines.
 #[derive(Debug)]
 pub struct Hunk<T : PartialEq + Clone> {
-    old_start : usize,
-    old_len : usize,
-    new_start : usize,
-    new_len : usize,
+    pub old_start : usize,
+    pub old_len : usize,
+    pub new_start : usize,
+    pub new_len : usize,
     pub items : Vec<DiffResult<T>>,
+    // The section heading (see -F) of the lines before the hunk, as
+    // found by the callers of the hunk state machine (see Sections).
+    pub section : Option<Vec<u8>>,
+    // Removed and added lines to be displayed together (see
+    // --pair-changes), as (old, new) offsets.
+    pub pairs : Vec<(usize, usize)>,
 }
 
 impl<T: PartialEq + Clone> Hunk<T> {
@@ -92,7 +131,9 @@ impl<T: PartialEq + Clone> Hunk<T> {
             old_len : 0,
             new_start : 0,
             new_len : 0,
-            items : vec![]
+            items : vec![],
+            section : None,
+            pairs : vec![],
         }
     }
     fn from_diff(d : &DiffResult<T>) -> Hunk<T> {
@@ -104,6 +145,8 @@ impl<T: PartialEq + Clone> Hunk<T> {
                     new_start : n,
                     new_len : 0,
                     items : vec![],
+                    section : None,
+                    pairs : vec![],
                 }
             },
             _ => {
@@ -138,6 +181,51 @@ impl<T: PartialEq + Clone> Hunk<T> {
         };
         self.items.push(d)
     }
+    // Make a copy of the hunk whose items index into line buffers that
+    // start at the given file offsets. The start offsets, which are what
+    // ends up in the hunk header, are left alone.
+    pub fn rebase(&self, old_base : usize, new_base : usize) -> Hunk<T> {
+        Hunk {
+            old_start : self.old_start,
+            old_len : self.old_len,
+            new_start : self.new_start,
+            new_len : self.new_len,
+            items : self.items.iter().map(|d| rebase_item(d, old_base, new_base)).collect(),
+            section : None,
+            pairs : vec![],
+        }
+    }
+}
+
+fn rebase_item<T : PartialEq + Clone>(d : &DiffResult<T>,
+                                      old_base : usize, new_base : usize) -> DiffResult<T> {
+    let rebase_el = |el : &DiffElement<T>| {
+        DiffElement {
+            old_index : el.old_index.map(|o| o - old_base),
+            new_index : el.new_index.map(|n| n - new_base),
+            data : el.data.clone(),
+        }
+    };
+    match d {
+        DiffResult::Common (el) => DiffResult::Common (rebase_el(el)),
+        DiffResult::Added (el) => DiffResult::Added (rebase_el(el)),
+        DiffResult::Removed (el) => DiffResult::Removed (rebase_el(el)),
+    }
+}
+
+// DiffResult isn't Clone, so neither can Hunk derive it.
+impl<T : PartialEq + Clone> Clone for Hunk<T> {
+    fn clone(&self) -> Hunk<T> {
+        Hunk {
+            old_start : self.old_start,
+            old_len : self.old_len,
+            new_start : self.new_start,
+            new_len : self.new_len,
+            items : self.items.iter().map(|d| rebase_item(d, 0, 0)).collect(),
+            section : self.section.clone(),
+            pairs : self.pairs.clone(),
+        }
+    }
 }
 
 fn do_context_write<T>(hunk : &Hunk<T>, conf : &Conf,
@@ -176,8 +264,8 @@ impl<'l> DisplayableHunk for Hunk<Word<'l>> {
     }
 }
 
-fn write_off_len(out : &mut Write,
-                 off : usize, len : usize) -> io::Result<()> {
+pub fn write_off_len(out : &mut Write,
+                     off : usize, len : usize) -> io::Result<()> {
     // Special case galore: if the len is zero, the line offset is that
     // of the previous line.
     if len == 0 {
@@ -194,79 +282,118 @@ fn write_off_len(out : &mut Write,
     Ok (())
 }
 
-fn write_hunk_header(out : &mut Write,
-                     hunk : &Hunk<Vec<u8>>) -> io::Result<()> {
+/// Finds the section heading (see -F) of each of the hunks of a file,
+/// i.e. the nearest line before the hunk that is matched by the section
+/// RE. Like diff, we pick up the search where we left it for the
+/// previous hunk, so that each line is only looked at once.
+pub struct Sections {
+    // File offset of the first line we've yet to look at.
+    scanned : usize,
+    last : Option<Vec<u8>>,
+}
+
+impl Default for Sections {
+    fn default() -> Sections {
+        Sections::new()
+    }
+}
+
+impl Sections {
+    pub fn new() -> Sections {
+        Sections {
+            scanned : 0,
+            last : None,
+        }
+    }
+
+    /// Look at the lines up to file offset `end`. The first of the lines
+    /// is at file offset `base`; any lines before that must have been
+    /// looked at already.
+    pub fn scan<L : AsRef<[u8]>>(&mut self, conf : &Conf, lines : &[L],
+                                 base : usize, end : usize) {
+        let re = match conf.section_re {
+            None => return,
+            Some (ref re) => re,
+        };
+        if end <= self.scanned {
+            return
+        }
+        let unseen = &lines[(self.scanned - base)..(end - base)];
+        if let Some (line) = unseen.iter().rev().map(|l| l.as_ref()).find(|l| re.is_match(l)) {
+            self.last = Some (line.to_vec())
+        }
+        self.scanned = end
+    }
+
+    /// The heading of a hunk starting at the last offset we scanned up to.
+    pub fn last(&self) -> Option<Vec<u8>> {
+        self.last.clone()
+    }
+}
+
+// Like diff, display at most 40 bytes of the section heading, w/o any
+// surrounding whitespace.
+fn write_section(out : &mut Write, section : Option<&[u8]>) -> io::Result<()> {
+    let line = match section {
+        None => return Ok (()),
+        Some (line) => line,
+    };
+    let is_space = |b : &u8| b" \t\x0b\x0c\r".contains(b);
+    let start = line.iter().take_while(|b| is_space(b)).count();
+    let line = &line[start..];
+    let mut end = line.iter().take(40).take_while(|&&b| b != b'\n').count();
+    while end > 0 && is_space(&line[end - 1]) {
+        end -= 1
+    }
+    out.write_all(b" ")?;
+    out.write_all(&line[..end])
+}
+
+fn write_hunk_header<T : PartialEq + Clone>(out : &mut Write, conf : &Conf,
+                                            hunk : &Hunk<T>,
+                                            section : Option<&[u8]>) -> io::Result<()> {
     let mut header = vec![];
     write!(header, "@@ -")?;
     write_off_len(&mut header, hunk.old_start, hunk.old_len)?;
     write!(header, " +")?;
     write_off_len(&mut header, hunk.new_start, hunk.new_len)?;
-    writeln!(header, " @@")?;
-    out.write_all(&header)
-}
-
-fn check_last_line_nl<'a, I>(old_lines : &[Vec<u8>], new_lines : &[Vec<u8>],
-                         items : I) -> (Option<bool>, Option<bool>)
-where
-    I : DoubleEndedIterator<Item=&'a DiffResult<Vec<u8>>>,
-{
-    let mut last_removed_nl = None;
-    let mut last_added_nl = None;
-    for d in items.rev() {
-        match d {
-            DiffResult::Common (_) => (),
-            DiffResult::Removed (r) => {
-                match last_removed_nl {
-                    Some (_) => (),
-                    None => {
-                        let o = r.old_index.unwrap();
-                        if o < (old_lines.len() - 1) {
-                            continue
-                        }
-                        let lo = &old_lines[o][..];
-                        last_removed_nl = Some (lo[lo.len() - 1] == b'\n');
-                        if last_added_nl.is_some() {
-                            break
-                        }
-                    }
-                }
-            },
-            DiffResult::Added (a) => {
-                match last_added_nl {
-                    Some (_) => (),
-                    None => {
-                        let n = a.new_index.unwrap();
-                        if n < (new_lines.len() - 1) {
-                            continue
-                        }
-                        let ln = &new_lines[n][..];
-                        last_added_nl = Some (ln[ln.len() - 1] == b'\n');
-                        if last_removed_nl.is_some() {
-                            break
-                        }
-                    }
-                }
-            },
-        }
-    }
-    (last_removed_nl, last_added_nl)
+    write!(header, " @@")?;
+    write_section(&mut header, section)?;
+    writeln!(header)?;
+    color::write_line(out, conf, color::HUNK, b"", &header)
 }
 
+// The prefixes are those of unchanged context lines and of context
+// lines that are marked as changed, which depend on the output format.
 fn output_context_line(out : &mut Write, conf : &Conf,
+                       pref : &[u8], marked_pref : &[u8],
                        line_o : &[u8], line_n : &[u8]) -> io::Result<()> {
     let diff = lcs_diff::diff::<u8>(line_o, line_n);
     if !super::exist_differences(&diff) {
-        out.write_all(b" ")?;
+        out.write_all(pref)?;
         out.write_all(line_o)?;
         return Ok (())
     }
     let mut buf : Vec<u8> = vec![];
     let pref = if conf.mark_changed_context {
-        b"!"
+        marked_pref
     } else {
-        b" "
+        pref
     };
     buf.write_all(pref)?;
+    let classes = match conf.context_format {
+        ContextLineFormat::CC (_) => !conf.cc_classes.is_empty(),
+        _ => false,
+    };
+    if classes {
+        let conf = Conf {context: usize::MAX, ..conf.clone()};
+        let words_o = tokenize_classes(&conf, line_o);
+        let words_n = tokenize_classes(&conf, line_n);
+        let diff = lcs_diff::diff::<Word>(&words_o[..], &words_n[..]);
+        display_diff_hunked::<Word>(&mut buf, &conf,
+                                    &words_o, &words_n, diff)?;
+        return out.write_all(&buf)
+    }
     match conf.context_tokenization {
         ContextLineTokenization::Char => {
             let conf = Conf {context: usize::MAX, ..conf.clone()};
@@ -274,10 +401,18 @@ fn output_context_line(out : &mut Write, conf : &Conf,
                                       line_o,
                                       line_n, diff)?;
         },
-        ContextLineTokenization::Word => {
+        ContextLineTokenization::Word (ref wb) => {
+            let conf = Conf {context: usize::MAX, ..conf.clone()};
+            let words_o = tokenize(wb, line_o);
+            let words_n = tokenize(wb, line_n);
+            let diff = lcs_diff::diff::<Word>(&words_o[..], &words_n[..]);
+            display_diff_hunked::<Word>(&mut buf, &conf,
+                                        &words_o, &words_n, diff)?;
+        },
+        ContextLineTokenization::Re (ref re) => {
             let conf = Conf {context: usize::MAX, ..conf.clone()};
-            let words_o = tokenize(line_o);
-            let words_n = tokenize(line_n);
+            let words_o = tokenize_re(re, line_o);
+            let words_n = tokenize_re(re, line_n);
             let diff = lcs_diff::diff::<Word>(&words_o[..], &words_n[..]);
             display_diff_hunked::<Word>(&mut buf, &conf,
                                         &words_o, &words_n, diff)?;
@@ -286,59 +421,319 @@ fn output_context_line(out : &mut Write, conf : &Conf,
     out.write_all(&buf)
 }
 
-impl DisplayableHunk for Hunk<Vec<u8>> {
-    type DiffItem = Vec<u8>;
-    fn do_write(&self, conf : &Conf, old_lines : &[Vec<u8>], new_lines : &[Vec<u8>],
-                out : &mut Write) -> io::Result<()> {
-        write_hunk_header(out, self)?;
+// Lines are either slices of the input files or, when streaming, copies
+// of the lines in the current window.
+fn write_line_hunk<L>(hunk : &Hunk<L>, conf : &Conf,
+                      old_lines : &[L], new_lines : &[L],
+                      out : &mut Write) -> io::Result<()>
+where L : AsRef<[u8]> + PartialEq + Clone
+{
+    let section = hunk.section.as_ref().map(|s| &s[..]);
+    match conf.output_format {
+        OutputFormat::Unified => {
+            write_hunk_header(out, conf, hunk, section)?;
+            write_unified_items(hunk, conf, old_lines, new_lines, out)
+        },
+        OutputFormat::SideBySide => {
+            // When the files are displayed in whole, there's only the one
+            // hunk, and nothing to locate it with a header.
+            if conf.context != usize::MAX {
+                write_hunk_header(out, conf, hunk, section)?;
+            }
+            sidebyside::write_items(out, conf, &hunk.items, old_lines, new_lines)
+        },
+        OutputFormat::Context => {
+            write_context_hunk(hunk, conf, section, old_lines, new_lines, out)
+        },
+        OutputFormat::Normal => write_normal_hunk(hunk, conf, old_lines, new_lines, out),
+        // JSON and HTML hunks need more than the lines (the selected
+        // keys, the selection itself), so they're written by the callers
+        // of the hunk state machine instead.
+        OutputFormat::Json | OutputFormat::Html => unreachable!(),
+    }
+}
+
+// What the unified format displays for a hunk: its items, except for
+// the removed and added lines that are displayed next to each other as
+// a pair.
+enum Row<'a, T : 'a + PartialEq + Clone> {
+    Item (&'a DiffResult<T>),
+    Pair (usize, usize),
+}
+
+// Within a run of changes, lines that come before a pair (in their own
+// file) are displayed before it.
+fn push_run<'a, T, I>(rows : &mut Vec<Row<'a, T>>, run : &mut Vec<&'a DiffResult<T>>,
+                      pairs : &mut Peekable<I>)
+where T : PartialEq + Clone, I : Iterator<Item=(usize, usize)>
+{
+    // Removed items might still carry a new offset and vice versa, so
+    // only look at the offset that matters.
+    let offset = |d : &DiffResult<T>| {
+        match d {
+            DiffResult::Removed (el) => (el.old_index, None),
+            DiffResult::Added (el) => (None, el.new_index),
+            DiffResult::Common (_) => (None, None),
+        }
+    };
+    let mut rest = mem::take(run);
+    loop {
+        let (o, n) = match pairs.peek() {
+            Some (&(o, n)) if rest.iter().any(|d| offset(d) == (Some (o), None)) => (o, n),
+            _ => break,
+        };
+        pairs.next();
+        let mut later = vec![];
+        for d in rest.drain(..) {
+            match offset(d) {
+                (Some (x), None) if x == o => (),
+                (None, Some (y)) if y == n => (),
+                (Some (x), None) if x < o => rows.push(Row::Item (d)),
+                (None, Some (y)) if y < n => rows.push(Row::Item (d)),
+                _ => later.push(d),
+            }
+        }
+        rows.push(Row::Pair (o, n));
+        rest = later
+    }
+    rows.extend(rest.into_iter().map(Row::Item))
+}
+
+fn unified_rows<'a, T : PartialEq + Clone>(hunk : &'a Hunk<T>) -> Vec<Row<'a, T>> {
+    let mut pairs = hunk.pairs.iter().cloned().peekable();
+    let mut rows = vec![];
+    let mut run = vec![];
+    for d in &hunk.items {
+        match d {
+            DiffResult::Common (_) => {
+                push_run(&mut rows, &mut run, &mut pairs);
+                rows.push(Row::Item (d))
+            },
+            _ => run.push(d),
+        }
+    }
+    push_run(&mut rows, &mut run, &mut pairs);
+    rows
+}
 
-        let (last_removed_nl, last_added_nl) =
-            check_last_line_nl(old_lines, new_lines, self.items.iter());
-        for d in &self.items {
+fn write_unified_items<L>(hunk : &Hunk<L>, conf : &Conf,
+                          old_lines : &[L], new_lines : &[L],
+                          out : &mut Write) -> io::Result<()>
+where L : AsRef<[u8]> + PartialEq + Clone
+{
+    // Like diff, note every line we display that's missing its EOL
+    // (which can only be the last line of either file).
+    for row in unified_rows(hunk) {
+        let d = match row {
+            Row::Item (d) => d,
+            Row::Pair (o, n) => {
+                // Still a removed and an added line, so that the hunk
+                // header adds up; only the highlighting shows the pair.
+                let line_o = old_lines[o].as_ref();
+                let line_n = new_lines[n].as_ref();
+                let (changed_o, changed_n) = sidebyside::changed_bytes(conf, line_o, line_n);
+                color::write_line_spans(out, conf, color::REMOVED, color::REMOVED_SPAN,
+                                        b"-", line_o, &changed_o)?;
+                check_no_newline(out, line_o)?;
+                color::write_line_spans(out, conf, color::ADDED, color::ADDED_SPAN,
+                                        b"+", line_n, &changed_n)?;
+                check_no_newline(out, line_n)?;
+                continue
+            },
+        };
+        match d {
+            DiffResult::Common (DiffElement { old_index : Some (o), new_index : Some (n), ..}) => {
+                let line_o = old_lines[*o].as_ref();
+                let line_n = new_lines[*n].as_ref();
+                output_context_line(out, conf, b" ", b"!", line_o, line_n)?;
+                check_no_newline(out, line_o)?;
+            },
+            DiffResult::Removed (DiffElement { old_index : Some (o), ..}) => {
+                let line_o = old_lines[*o].as_ref();
+                color::write_line(out, conf, color::REMOVED, b"-", line_o)?;
+                check_no_newline(out, line_o)?;
+            },
+            DiffResult::Added (DiffElement { new_index : Some (n), ..}) => {
+                let line_n = new_lines[*n].as_ref();
+                color::write_line(out, conf, color::ADDED, b"+", line_n)?;
+                check_no_newline(out, line_n)?;
+            },
+            _ => panic!("Can't print DiffElement with neither side"),
+        }
+    };
+    Ok (())
+}
+
+// Line ranges, as displayed by the context and normal formats: the
+// first and last line, or just the one line. An empty range is
+// displayed as the line before it.
+fn write_range(out : &mut Write, off : usize, len : usize) -> io::Result<()> {
+    match len {
+        0 => write!(out, "{}", off),
+        1 => write!(out, "{}", off + 1),
+        _ => write!(out, "{},{}", off + 1, off + len),
+    }
+}
+
+// Unlike the unified format, the context and normal formats don't need
+// to care whether the other file ends with a newline too.
+fn check_no_newline(out : &mut Write, line : &[u8]) -> io::Result<()> {
+    match line.last() {
+        Some (b'\n') => Ok (()),
+        _ => out.write_all(b"\n\\ No newline at end of file\n"),
+    }
+}
+
+// For the context format, find which of the items are part of a change,
+// i.e. a run of removals and additions that has both.
+fn changed_items<T : PartialEq + Clone>(items : &[DiffResult<T>]) -> Vec<bool> {
+    let mut changed = vec![false; items.len()];
+    let mut i = 0;
+    while i < items.len() {
+        let start = i;
+        let mut removed = false;
+        let mut added = false;
+        while i < items.len() {
+            match items[i] {
+                DiffResult::Common (_) => break,
+                DiffResult::Removed (_) => removed = true,
+                DiffResult::Added (_) => added = true,
+            }
+            i += 1
+        }
+        if removed && added {
+            for c in &mut changed[start..i] {
+                *c = true
+            }
+        }
+        if i == start {
+            i += 1
+        }
+    }
+    changed
+}
+
+// The context format of diff -c: the old lines of the hunk, followed by
+// the new ones. Each half is omitted when it only has context lines.
+fn write_context_hunk<L>(hunk : &Hunk<L>, conf : &Conf, section : Option<&[u8]>,
+                         old_lines : &[L], new_lines : &[L],
+                         out : &mut Write) -> io::Result<()>
+where L : AsRef<[u8]> + PartialEq + Clone
+{
+    let changed = changed_items(&hunk.items);
+    let has_removed = hunk.items.iter().any(|d| matches!(d, DiffResult::Removed (_)));
+    let has_added = hunk.items.iter().any(|d| matches!(d, DiffResult::Added (_)));
+    let mut header = vec![];
+    write!(header, "***************")?;
+    write_section(&mut header, section)?;
+    writeln!(header)?;
+    color::write_line(out, conf, color::HUNK, b"", &header)?;
+    let mut header = vec![];
+    write!(header, "*** ")?;
+    write_range(&mut header, hunk.old_start, hunk.old_len)?;
+    writeln!(header, " ****")?;
+    color::write_line(out, conf, color::HUNK, b"", &header)?;
+    if has_removed {
+        for (d, &changed) in hunk.items.iter().zip(&changed) {
             match d {
                 DiffResult::Common (DiffElement { old_index : Some (o), new_index : Some (n), ..}) => {
-                    let line_o = &old_lines[*o][..];
-                    let line_n = &new_lines[*n][..];
-                    output_context_line(out, conf, line_o, line_n)?;
+                    let line_o = old_lines[*o].as_ref();
+                    output_context_line(out, conf, b"  ", b"! ", line_o, new_lines[*n].as_ref())?;
+                    check_no_newline(out, line_o)?;
                 },
                 DiffResult::Removed (DiffElement { old_index : Some (o), ..}) => {
-                    out.write_all(b"-")?;
-                    out.write_all(&old_lines[*o][..])?;
-                    if *o == (old_lines.len() - 1) {
-                        match (last_removed_nl, last_added_nl) {
-                            (Some (o_has_nl), Some (n_has_nl)) => {
-                                if !o_has_nl && n_has_nl {
-                                    out.write_all(b"\n\\ No newline at end of file\n")?;
-                                }
-                            },
-                            (Some (false), None) => {
-                                out.write_all(b"\n\\ No newline at end of file\n")?;
-                            },
-                            _ => (),
-                        }
-                    }
+                    let line_o = old_lines[*o].as_ref();
+                    let pref = if changed { b"! " } else { b"- " };
+                    color::write_line(out, conf, color::REMOVED, pref, line_o)?;
+                    check_no_newline(out, line_o)?;
+                },
+                DiffResult::Added (_) => (),
+                _ => panic!("Can't print DiffElement with neither side"),
+            }
+        }
+    }
+    let mut header = vec![];
+    write!(header, "--- ")?;
+    write_range(&mut header, hunk.new_start, hunk.new_len)?;
+    writeln!(header, " ----")?;
+    color::write_line(out, conf, color::HUNK, b"", &header)?;
+    if has_added {
+        for (d, &changed) in hunk.items.iter().zip(&changed) {
+            match d {
+                DiffResult::Common (DiffElement { old_index : Some (o), new_index : Some (n), ..}) => {
+                    let line_n = new_lines[*n].as_ref();
+                    output_context_line(out, conf, b"  ", b"! ", old_lines[*o].as_ref(), line_n)?;
+                    check_no_newline(out, line_n)?;
                 },
                 DiffResult::Added (DiffElement { new_index : Some (n), ..}) => {
-                    out.write_all(b"+")?;
-                    out.write_all(&new_lines[*n][..])?;
-                    if *n == (new_lines.len() - 1) {
-                        match (last_removed_nl, last_added_nl) {
-                            (Some (o_has_nl), Some (n_has_nl)) => {
-                                if o_has_nl && !n_has_nl {
-                                    out.write_all(b"\n\\ No newline at end of file\n")?;
-                                }
-                            },
-                            (None, Some (false)) => {
-                                out.write_all(b"\n\\ No newline at end of file\n")?;
-                            },
-                            _ => (),
-                        }
-                    }
+                    let line_n = new_lines[*n].as_ref();
+                    let pref = if changed { b"! " } else { b"+ " };
+                    color::write_line(out, conf, color::ADDED, pref, line_n)?;
+                    check_no_newline(out, line_n)?;
                 },
+                DiffResult::Removed (_) => (),
                 _ => panic!("Can't print DiffElement with neither side"),
             }
-        };
-        Ok (())
+        }
+    }
+    Ok (())
+}
+
+// The normal format of diff: no context, just an ed-like command
+// (e.g. `3c3`) followed by the removed and added lines. The hunks are
+// expected to have been built w/o context, so that each one holds a
+// single change.
+fn write_normal_hunk<L>(hunk : &Hunk<L>, conf : &Conf,
+                        old_lines : &[L], new_lines : &[L],
+                        out : &mut Write) -> io::Result<()>
+where L : AsRef<[u8]> + PartialEq + Clone
+{
+    let cmd = if hunk.old_len == 0 {
+        "a"
+    } else if hunk.new_len == 0 {
+        "d"
+    } else {
+        "c"
+    };
+    let mut header = vec![];
+    write_range(&mut header, hunk.old_start, hunk.old_len)?;
+    write!(header, "{}", cmd)?;
+    write_range(&mut header, hunk.new_start, hunk.new_len)?;
+    writeln!(header)?;
+    color::write_line(out, conf, color::HUNK, b"", &header)?;
+    for d in &hunk.items {
+        if let DiffResult::Removed (DiffElement { old_index : Some (o), ..}) = d {
+            let line_o = old_lines[*o].as_ref();
+            color::write_line(out, conf, color::REMOVED, b"< ", line_o)?;
+            check_no_newline(out, line_o)?;
+        }
+    }
+    if cmd == "c" {
+        out.write_all(b"---\n")?;
+    }
+    for d in &hunk.items {
+        if let DiffResult::Added (DiffElement { new_index : Some (n), ..}) = d {
+            let line_n = new_lines[*n].as_ref();
+            color::write_line(out, conf, color::ADDED, b"> ", line_n)?;
+            check_no_newline(out, line_n)?;
+        }
+    }
+    Ok (())
+}
+
+impl DisplayableHunk for Hunk<Vec<u8>> {
+    type DiffItem = Vec<u8>;
+    fn do_write(&self, conf : &Conf, old_lines : &[Vec<u8>], new_lines : &[Vec<u8>],
+                out : &mut Write) -> io::Result<()> {
+        write_line_hunk(self, conf, old_lines, new_lines, out)
+    }
+}
+
+impl<'l> DisplayableHunk for Hunk<&'l [u8]> {
+    type DiffItem = &'l [u8];
+    fn do_write(&self, conf : &Conf, old_lines : &[&[u8]], new_lines : &[&[u8]],
+                out : &mut Write) -> io::Result<()> {
+        write_line_hunk(self, conf, old_lines, new_lines, out)
     }
 }
 
@@ -625,6 +1020,160 @@ Hunk<T> : DisplayableHunk<DiffItem=T>
     Ok (state)
 }
 
+/// Groups a stream of diff results into hunks, handing each hunk to
+/// `dump_hunk` as soon as it's complete. The diff results can be fed
+/// in incrementally, so the caller doesn't need to hold all of them
+/// (or all of the input lines) in memory at once.
+pub struct HunkBuilder<T : PartialEq + Clone + Debug> {
+    offsets : FileOffsets,
+    state : Option<State<T>>,
+}
+
+impl<T> HunkBuilder<T>
+where T : PartialEq + Clone + Debug,
+Hunk<T> : DisplayableHunk<DiffItem=T>
+{
+    pub fn new() -> HunkBuilder<T> {
+        HunkBuilder {
+            offsets : FileOffsets {
+                old_off : 0,
+                new_off : 0,
+            },
+            state : None,
+        }
+    }
+
+    pub fn push(&mut self, conf : &Conf,
+                dump_hunk : &mut FnMut(Option<&Hunk<T>>) -> io::Result<()>,
+                mut d : DiffResult<T>) -> io::Result<()> {
+        dprintln!(conf.debug, "offsets: {:?}, state = {:?}", self.offsets, self.state);
+        dprintln!(conf.debug, "processing diff result: {:?}", d);
+        update_indices(&mut d, &self.offsets);
+        self.offsets.observe(&d);
+        let state = match self.state.take() {
+            // If the first diff result is an add or a remove, we need
+            // to manually note down the start line in the hunk
+            None => {
+                if conf.context > 0 {
+                    setup_initial_state(d)
+                } else {
+                    setup_initial_state_nocontext(d)
+                }
+            },
+            Some (state) => {
+                if conf.context > 0 {
+                    fsm(conf, dump_hunk, state, d)?
+                } else {
+                    fsm_nocontext(conf, dump_hunk, state, d)?
+                }
+            },
+        };
+        self.state = Some (state);
+        Ok (())
+    }
+
+    // The lowest offsets in the old and new files that might still
+    // be referenced by a hunk we've yet to dump. Lines before those
+    // offsets are no longer needed for producing the output.
+    pub fn pending_offsets(&self) -> (usize, usize) {
+        use self::State::*;
+        let mut old_off = self.offsets.old_off;
+        let mut new_off = self.offsets.new_off;
+        {
+            let mut observe = |o : usize, n : usize| {
+                old_off = cmp::min(old_off, o);
+                new_off = cmp::min(new_off, n);
+            };
+            let (hunk, pending) = match self.state {
+                None => return (old_off, new_off),
+                Some (CollectingAdds (ref hunk, ref adds))
+                    | Some (SequentialRemoves (ref hunk, ref adds)) => (hunk, adds.first()),
+                Some (CollectingCommonsTail (ref hunk, _, ref commons))
+                    | Some (CollectingCommonsCorked (ref hunk, ref commons)) => {
+                        (hunk, commons.front())
+                    },
+            };
+            if let Some (ref h) = *hunk {
+                observe(h.old_start, h.new_start)
+            }
+            if let Some (d) = pending {
+                if let (Some (o), Some (n)) = diff_offsets(d) {
+                    observe(o, n)
+                }
+            }
+        }
+        (old_off, new_off)
+    }
+
+    /// If the hunk we're building holds more than `max_len` diff results,
+    /// dump what we have so far and carry on with a new hunk, so that we
+    /// never hold on to more than that. The hunks are still valid, there
+    /// just isn't any context between the two halves of a split change.
+    pub fn split(&mut self, conf : &Conf,
+                 dump_hunk : &mut FnMut(Option<&Hunk<T>>) -> io::Result<()>,
+                 max_len : usize) -> io::Result<()> {
+        use self::State::*;
+        let hunk_len = |hunk : &Option<Hunk<T>>| hunk.as_ref().map_or(0, |h| h.items.len());
+        let len = match self.state {
+            None => return Ok (()),
+            Some (CollectingAdds (ref hunk, ref adds))
+                | Some (SequentialRemoves (ref hunk, ref adds)) => hunk_len(hunk) + adds.len(),
+            Some (CollectingCommonsTail (ref hunk, _, ref commons))
+                | Some (CollectingCommonsCorked (ref hunk, ref commons)) => {
+                    hunk_len(hunk) + commons.len()
+                },
+        };
+        if len <= max_len {
+            return Ok (())
+        }
+        dprintln!(conf.debug, "Splitting hunk of {} diff results", len);
+        let state = match self.state.take().unwrap() {
+            CollectingAdds (mut hunk, mut adds) => {
+                consume(&mut hunk, &mut adds.drain(..));
+                dump_hunk(hunk.as_ref())?;
+                CollectingAdds (None, adds)
+            },
+            SequentialRemoves (mut hunk, mut adds) => {
+                consume(&mut hunk, &mut adds.drain(..));
+                dump_hunk(hunk.as_ref())?;
+                SequentialRemoves (None, adds)
+            },
+            // The commons we're holding on to are the leading context
+            // of the next hunk, if any.
+            CollectingCommonsTail (hunk, seen, commons) => {
+                dump_hunk(hunk.as_ref())?;
+                CollectingCommonsTail (None, seen, commons)
+            },
+            CollectingCommonsCorked (mut hunk, mut commons) => {
+                consume(&mut hunk, &mut commons.drain(..));
+                dump_hunk(hunk.as_ref())?;
+                CollectingCommonsTail (None, 0, commons)
+            },
+        };
+        self.state = Some (state);
+        Ok (())
+    }
+
+    pub fn finish(self, conf : &Conf,
+                  dump_hunk : &mut FnMut(Option<&Hunk<T>>) -> io::Result<()>)
+                  -> io::Result<()> {
+        dprintln!(conf.debug, "offsets[before final]: {:?}", self.offsets);
+        match self.state {
+            None => Ok (()),
+            Some (state) => handle_final_state(conf, dump_hunk, state),
+        }
+    }
+}
+
+impl<T> Default for HunkBuilder<T>
+where T : PartialEq + Clone + Debug,
+Hunk<T> : DisplayableHunk<DiffItem=T>
+{
+    fn default() -> HunkBuilder<T> {
+        HunkBuilder::new()
+    }
+}
+
 pub fn display_diff_hunked<T>(
     out : &mut Write,
     conf : &Conf,
@@ -634,10 +1183,6 @@ pub fn display_diff_hunked<T>(
 where T : PartialEq + Clone + Debug,
 Hunk<T> : DisplayableHunk<DiffItem=T>
 {
-    let mut offsets = FileOffsets {
-        old_off : 0,
-        new_off : 0,
-    };
     let mut dump_hunk = |hunk : Option<&Hunk<T>>| {
         match hunk {
             None => Ok (()),
@@ -646,34 +1191,23 @@ Hunk<T> : DisplayableHunk<DiffItem=T>
             }
         }
     };
-    let mut diff_results = diff.into_iter();
-    let mut first_diff = match diff_results.next() {
-        Some (d) => d,
-        None => panic!("No differences at all, shouldn't have been called"),
-    };
-    dprintln!(conf.debug, "first diff: {:?}", first_diff);
-    update_indices(&mut first_diff, &offsets);
-    offsets.observe(&first_diff);
-    // If the first diff result is an add or a remove, we need
-    // to manually note down the start line in the hunk
-    let mut state = if conf.context > 0 {
-        setup_initial_state(first_diff)
-    } else {
-        setup_initial_state_nocontext(first_diff)
-    };
+    for_each_hunk(conf, diff, &mut dump_hunk)?;
+    Ok (1)
+}
 
-    for mut d in diff_results {
-        dprintln!(conf.debug, "offsets: {:?}, state = {:?}", offsets, state);
-        dprintln!(conf.debug, "processing diff result: {:?}", d);
-        update_indices(&mut d, &offsets);
-        offsets.observe(&d);
-        state = if conf.context > 0 {
-            fsm(conf, &mut dump_hunk, state, d)?
-        } else {
-            fsm_nocontext(conf, &mut dump_hunk, state, d)?
-        };
+/// Group the diff results into hunks and pass each one to `dump_hunk`.
+pub fn for_each_hunk<T>(conf : &Conf, diff : Vec<DiffResult<T>>,
+                        dump_hunk : &mut FnMut(Option<&Hunk<T>>) -> io::Result<()>)
+                        -> io::Result<()>
+where T : PartialEq + Clone + Debug,
+Hunk<T> : DisplayableHunk<DiffItem=T>
+{
+    if diff.is_empty() {
+        panic!("No differences at all, shouldn't have been called")
     }
-    dprintln!(conf.debug, "offsets[before final]: {:?}", offsets);
-    handle_final_state(conf, &mut dump_hunk, state)?;
-    Ok (1)
+    let mut builder = HunkBuilder::new();
+    for d in diff {
+        builder.push(conf, dump_hunk, d)?;
+    }
+    builder.finish(conf, dump_hunk)
 }
@@ -0,0 +1,238 @@
+// JSON output, meant for consumption by other tools. Every hunk is
+// written out as a JSON object on a line of its own, so the output can
+// be processed a line at a time, even when comparing directories or
+// streaming. Input is arbitrary bytes: text that is not valid UTF-8 is
+// written with U+FFFD in place of the invalid sequences, and the bytes
+// themselves are given, base64-encoded, in a field of the same name
+// with a `_b64` suffix.
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use super::lcs_diff;
+use super::lcs_diff::DiffResult;
+use conf::{Conf, ContextLineTokenization};
+use hunked::{Hunk, Side};
+use wdiff::{Writeable, tokenize, tokenize_re};
+
+fn string(buf : &mut Vec<u8>, s : &[u8]) {
+    buf.push(b'"');
+    for ch in String::from_utf8_lossy(s).chars() {
+        match ch {
+            '"' => buf.extend_from_slice(b"\\\""),
+            '\\' => buf.extend_from_slice(b"\\\\"),
+            '\n' => buf.extend_from_slice(b"\\n"),
+            '\r' => buf.extend_from_slice(b"\\r"),
+            '\t' => buf.extend_from_slice(b"\\t"),
+            ch if (ch as u32) < 0x20 => {
+                write!(buf, "\\u{:04x}", ch as u32).unwrap()
+            },
+            ch => {
+                let mut utf8 = [0; 4];
+                buf.extend_from_slice(ch.encode_utf8(&mut utf8).as_bytes())
+            },
+        }
+    }
+    buf.push(b'"');
+}
+
+fn field(buf : &mut Vec<u8>, first : bool, name : &str) {
+    if !first {
+        buf.push(b',')
+    }
+    string(buf, name.as_bytes());
+    buf.push(b':');
+}
+
+const BASE64 : &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(buf : &mut Vec<u8>, s : &[u8]) {
+    buf.push(b'"');
+    for chunk in s.chunks(3) {
+        let mut group = [0; 3];
+        group[..chunk.len()].copy_from_slice(chunk);
+        let bits = (group[0] as u32) << 16 | (group[1] as u32) << 8 | group[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                buf.push(BASE64[((bits >> (18 - 6 * i)) & 0x3f) as usize])
+            } else {
+                buf.push(b'=')
+            }
+        }
+    }
+    buf.push(b'"');
+}
+
+// A field holding input text, along with its lossless counterpart when
+// the text is not valid UTF-8.
+fn text_field(buf : &mut Vec<u8>, first : bool, name : &str, s : &[u8]) {
+    field(buf, first, name);
+    string(buf, s);
+    if ::std::str::from_utf8(s).is_err() {
+        field(buf, false, &format!("{}_b64", name));
+        base64(buf, s);
+    }
+}
+
+fn lineno(buf : &mut Vec<u8>, off : Option<usize>) {
+    match off {
+        None => buf.extend_from_slice(b"null"),
+        Some (off) => write!(buf, "{}", off + 1).unwrap(),
+    }
+}
+
+// Offsets as they appear in the unified diff hunk header.
+fn start(off : usize, len : usize) -> usize {
+    if len == 0 {
+        off
+    } else {
+        off + 1
+    }
+}
+
+fn push_run(runs : &mut Vec<(&'static str, Vec<u8>)>, kind : &'static str, text : Vec<u8>) {
+    if text.is_empty() {
+        return
+    }
+    if let Some (run) = runs.last_mut() {
+        if run.0 == kind {
+            run.1.extend(text);
+            return
+        }
+    }
+    runs.push((kind, text))
+}
+
+// The intra-line diff of a context line, as a list of runs of tokens
+// that are common, removed or added. Like in the rest of the output,
+// removals come before additions.
+fn changes<T>(buf : &mut Vec<u8>, old : &[T], new : &[T])
+where T : PartialEq + Clone + Writeable
+{
+    let mut runs = vec![];
+    let mut removed = vec![];
+    let mut added = vec![];
+    for d in lcs_diff::diff(old, new) {
+        match d {
+            DiffResult::Common (el) => {
+                push_run(&mut runs, "removed", removed.split_off(0));
+                push_run(&mut runs, "added", added.split_off(0));
+                let mut text = vec![];
+                el.data.write_to(&mut text).unwrap();
+                push_run(&mut runs, "common", text)
+            },
+            DiffResult::Removed (el) => el.data.write_to(&mut removed).unwrap(),
+            DiffResult::Added (el) => el.data.write_to(&mut added).unwrap(),
+        }
+    }
+    push_run(&mut runs, "removed", removed);
+    push_run(&mut runs, "added", added);
+    buf.push(b'[');
+    for (i, &(kind, ref text)) in runs.iter().enumerate() {
+        if i > 0 {
+            buf.push(b',')
+        }
+        buf.push(b'{');
+        field(buf, true, "kind");
+        string(buf, kind.as_bytes());
+        text_field(buf, false, "text", text);
+        buf.push(b'}');
+    }
+    buf.push(b']');
+}
+
+fn item<L, K>(buf : &mut Vec<u8>, conf : &Conf, d : &DiffResult<L>,
+              old : &Side<L, K>, new : &Side<L, K>)
+where L : AsRef<[u8]> + PartialEq + Clone, K : AsRef<[u8]>
+{
+    let (kind, o, n) = match d {
+        DiffResult::Common (el) => ("common", el.old_index, el.new_index),
+        DiffResult::Removed (el) => ("removed", el.old_index, None),
+        DiffResult::Added (el) => ("added", None, el.new_index),
+    };
+    buf.push(b'{');
+    field(buf, true, "kind");
+    string(buf, kind.as_bytes());
+    field(buf, false, "old_line");
+    lineno(buf, o);
+    field(buf, false, "new_line");
+    lineno(buf, n);
+    if let Some (o) = o {
+        text_field(buf, false, "old_text", old.line(o));
+        text_field(buf, false, "old_key", old.key(o));
+    }
+    if let Some (n) = n {
+        text_field(buf, false, "new_text", new.line(n));
+        text_field(buf, false, "new_key", new.key(n));
+    }
+    if let (Some (o), Some (n)) = (o, n) {
+        let (line_o, line_n) = (old.line(o), new.line(n));
+        if line_o != line_n {
+            field(buf, false, "changes");
+            match conf.context_tokenization {
+                ContextLineTokenization::Char => changes(buf, line_o, line_n),
+                ContextLineTokenization::Word (ref wb) => {
+                    changes(buf, &tokenize(wb, line_o), &tokenize(wb, line_n))
+                },
+                ContextLineTokenization::Re (ref re) => {
+                    changes(buf, &tokenize_re(re, line_o), &tokenize_re(re, line_n))
+                },
+            }
+        }
+    }
+    buf.push(b'}');
+}
+
+/// Write out a hunk as a single line of JSON.
+pub fn write_hunk<L, K>(out : &mut Write, conf : &Conf, hunk : &Hunk<L>,
+                        old : &Side<L, K>, new : &Side<L, K>) -> io::Result<()>
+where L : AsRef<[u8]> + PartialEq + Clone, K : AsRef<[u8]>
+{
+    let mut buf = vec![];
+    buf.push(b'{');
+    text_field(&mut buf, true, "old_file", old.path.as_os_str().as_bytes());
+    text_field(&mut buf, false, "new_file", new.path.as_os_str().as_bytes());
+    field(&mut buf, false, "old_start");
+    write!(buf, "{}", start(hunk.old_start, hunk.old_len))?;
+    field(&mut buf, false, "old_len");
+    write!(buf, "{}", hunk.old_len)?;
+    field(&mut buf, false, "new_start");
+    write!(buf, "{}", start(hunk.new_start, hunk.new_len))?;
+    field(&mut buf, false, "new_len");
+    write!(buf, "{}", hunk.new_len)?;
+    field(&mut buf, false, "items");
+    buf.push(b'[');
+    for (i, d) in hunk.items.iter().enumerate() {
+        if i > 0 {
+            buf.push(b',')
+        }
+        item(&mut buf, conf, d, old, new)
+    }
+    buf.extend_from_slice(b"]}\n");
+    out.write_all(&buf)
+}
+
+/// The JSON counterpart of the "Only in" lines of `diff -r`.
+pub fn write_only_in(out : &mut Write, dir : &Path, name : &[u8]) -> io::Result<()> {
+    let mut buf = vec![];
+    buf.push(b'{');
+    text_field(&mut buf, true, "only_in", dir.as_os_str().as_bytes());
+    text_field(&mut buf, false, "name", name);
+    buf.extend_from_slice(b"}\n");
+    out.write_all(&buf)
+}
+
+/// Reported when one of the paths is a directory and the other is not.
+pub fn write_kind_mismatch(out : &mut Write, old : &Path, new : &Path,
+                           old_kind : &[u8], new_kind : &[u8]) -> io::Result<()> {
+    let mut buf = vec![];
+    buf.push(b'{');
+    text_field(&mut buf, true, "old_file", old.as_os_str().as_bytes());
+    field(&mut buf, false, "old_kind");
+    string(&mut buf, old_kind);
+    text_field(&mut buf, false, "new_file", new.as_os_str().as_bytes());
+    field(&mut buf, false, "new_kind");
+    string(&mut buf, new_kind);
+    buf.extend_from_slice(b"}\n");
+    out.write_all(&buf)
+}
@@ -12,20 +12,31 @@ extern crate itertools;
 extern crate temporary;
 extern crate clap;
 extern crate regex;
-#[cfg(test)]
 extern crate rayon;
+extern crate memmap;
+extern crate atty;
+#[cfg(feature = "fancy")]
+extern crate fancy_regex;
+extern crate toml;
 
 use self::lcs_diff::*;
 use std::io;
 use std::io::prelude::*;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
-use clap::{App, Arg};
+use std::cmp;
+use std::hash::Hash;
+use std::collections::HashMap;
+use std::borrow::Cow;
+use std::ops::{Deref, Range};
+use clap::{App, Arg, ArgMatches};
 use std::os::unix::ffi::OsStringExt;
 use regex::bytes::{Regex, RegexSet, RegexBuilder, RegexSetBuilder};
 use chrono::{DateTime, Local};
+use rayon::prelude::*;
+use memmap::Mmap;
 
 macro_rules! dprintln {
     ($dbg:expr, $fmt:expr, $( $args:expr ),*) => {
@@ -37,8 +48,21 @@ macro_rules! dprintln {
     }
 }
 
+pub mod color;
 pub mod conf;
+pub mod dirdiff;
+pub mod engine;
+pub mod histogram;
+mod html;
 pub mod hunked;
+pub mod json;
+pub mod myers;
+pub mod pairing;
+pub mod patience;
+pub mod profile;
+pub mod rename;
+pub mod sidebyside;
+mod stream;
 pub mod wdiff;
 
 #[cfg(test)]
@@ -46,32 +70,122 @@ pub mod tests;
 
 use conf::*;
 use hunked::*;
+use rename::Renaming;
+use engine::Re;
+
+// As with diff, `-` stands for the standard input.
+fn is_stdin(p : &Path) -> bool {
+    p.as_os_str() == "-"
+}
+
+fn open_input(p : &Path) -> io::Result<Box<BufRead + Send>> {
+    if is_stdin(p) {
+        Ok (Box::new(io::BufReader::new(io::stdin())))
+    } else {
+        Ok (Box::new(io::BufReader::new(File::open(p)?)))
+    }
+}
+
+// The contents of an input file. Regular files are mapped into memory;
+// anything else (the standard input, pipes, ...) is read into a buffer.
+// Either way, the lines we diff are slices of this.
+enum Input {
+    Mapped (Mmap),
+    Buffered (Vec<u8>),
+}
+
+impl Deref for Input {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Input::Mapped (ref m) => &m[..],
+            Input::Buffered (ref b) => &b[..],
+        }
+    }
+}
 
-fn read_lines(p : &Path) -> io::Result<Vec<Vec<u8>>> {
-    let f = File::open(p)?;
-    let mut f = io::BufReader::new(f);
+fn read_input(p : &Path) -> io::Result<Input> {
+    let mut buf = vec![];
+    if is_stdin(p) {
+        io::stdin().read_to_end(&mut buf)?;
+        return Ok (Input::Buffered (buf))
+    }
+    let mut f = File::open(p)?;
+    let meta = f.metadata()?;
+    // Empty files can't be mapped.
+    if meta.file_type().is_file() && meta.len() > 0 {
+        // This is only unsafe if the file is modified while we're
+        // looking at it, which would make for a confusing diff
+        // in any case.
+        return Ok (Input::Mapped (unsafe { Mmap::map(&f)? }))
+    }
+    // Read from the file we already have open: the writer of a FIFO
+    // may be gone by the time we could open it again.
+    f.read_to_end(&mut buf)?;
+    Ok (Input::Buffered (buf))
+}
+
+// Split the input into lines, each one including its EOL character.
+// Only the last line can be missing the EOL.
+fn split_lines(buf : &[u8]) -> Vec<&[u8]> {
     let mut ret = vec![];
-    loop {
-        let mut buf = vec![];
-        let len = f.read_until(b'\n', &mut buf)?;
-        if len == 0 {
-            return Ok (ret)
+    let mut start = 0;
+    for (i, &c) in buf.iter().enumerate() {
+        if c == b'\n' {
+            ret.push(&buf[start..(i + 1)]);
+            start = i + 1
         }
-        ret.push(buf)
     }
+    if start < buf.len() {
+        ret.push(&buf[start..])
+    }
+    ret
 }
 
-fn file_header(out : &mut Write, prefix : &[u8], path : &Path) -> io::Result<()> {
+fn modification_time(path : &Path) -> io::Result<DateTime<Local>> {
+    // Pipes, terminals and the like don't have a meaningful modification
+    // time, so do what diff does and use the current time instead.
+    if is_stdin(path) {
+        return Ok (Local::now())
+    }
     let meta = path.metadata()?;
-    let modified = meta.modified()?;
-    let dt : DateTime<Local> = DateTime::from(modified);
+    if meta.file_type().is_file() {
+        Ok (DateTime::from(meta.modified()?))
+    } else {
+        Ok (Local::now())
+    }
+}
+
+fn file_header(out : &mut Write, conf : &Conf, prefix : &[u8], path : &Path) -> io::Result<()> {
+    let dt = modification_time(path)?;
     let mut acc = vec![];
-    acc.write_all(prefix)?;
     acc.write_all(b" ")?;
     acc.write_all(&path.as_os_str().to_os_string().into_vec())?;
     acc.write_all(b"\t")?;
-    writeln!(acc, "{}", dt.format("%Y-%m-%d %H:%M:%S.%f %z"))?;
-    out.write_all(&acc)
+    // Like diff, use the traditional timestamp format in context diffs.
+    let fmt = if conf.output_format == OutputFormat::Context {
+        "%a %b %e %H:%M:%S %Y"
+    } else {
+        "%Y-%m-%d %H:%M:%S.%f %z"
+    };
+    writeln!(acc, "{}", dt.format(fmt))?;
+    color::write_line(out, conf, color::HEADER, prefix, &acc)
+}
+
+// The headers naming the two files, if any, in the current output format.
+fn file_headers(out : &mut Write, conf : &Conf, old : &Path, new : &Path) -> io::Result<()> {
+    match conf.output_format {
+        // Like diff -y, which doesn't name the files either.
+        OutputFormat::Normal | OutputFormat::SideBySide => Ok (()),
+        OutputFormat::Context => {
+            file_header(out, conf, b"***", old)?;
+            file_header(out, conf, b"---", new)
+        },
+        _ => {
+            file_header(out, conf, b"---", old)?;
+            file_header(out, conf, b"+++", new)
+        },
+    }
 }
 
 fn exist_differences<T : PartialEq + Clone>(results : &[DiffResult<T>]) -> bool {
@@ -81,7 +195,187 @@ fn exist_differences<T : PartialEq + Clone>(results : &[DiffResult<T>]) -> bool
     })
 }
 
-fn sel_part_of_line(conf : &Conf, re : &Regex, line : &[u8]) -> Option<Vec<u8>> {
+// Compare the lines (or their selected parts) using the algorithm
+// the user asked for.
+fn diff_lines<T : Eq + Hash + Clone>(conf : &Conf, old : &[T], new : &[T])
+                                     -> Vec<DiffResult<T>> {
+    match conf.diff_algorithm {
+        DiffAlgorithm::Lcs => lcs_diff::diff(old, new),
+        DiffAlgorithm::Myers => myers::diff(old, new),
+        DiffAlgorithm::Patience => patience::diff(old, new),
+        DiffAlgorithm::Histogram => histogram::diff(old, new),
+    }
+}
+
+// Map each distinct key to a small integer, so that the diff algorithm
+// gets to compare (and hash) integers instead of whole lines.
+fn intern<'a, K : AsRef<[u8]>>(ids : &mut HashMap<&'a [u8], usize>,
+                               keys : &'a [K]) -> Vec<usize> {
+    keys.iter().map(|k| {
+        let next = ids.len();
+        *ids.entry(k.as_ref()).or_insert(next)
+    }).collect()
+}
+
+fn line_result<L>(d : DiffResult<usize>, old_off : usize, new_off : usize) -> DiffResult<L>
+where L : PartialEq + Clone + Default
+{
+    // The hunks only ever look at the line offsets; there's no point
+    // in carrying around a copy of the key.
+    let el = |el : DiffElement<usize>| DiffElement {
+        old_index : el.old_index.map(|o| o + old_off),
+        new_index : el.new_index.map(|n| n + new_off),
+        data : L::default(),
+    };
+    match d {
+        DiffResult::Common (e) => DiffResult::Common (el(e)),
+        DiffResult::Removed (e) => DiffResult::Removed (el(e)),
+        DiffResult::Added (e) => DiffResult::Added (el(e)),
+    }
+}
+
+/// Diff the keys (i.e. the selected parts) of the lines of two files.
+/// When comparing up to a renaming, the values to be renamed are masked
+/// out of the keys; it's up to the caller to check the common lines
+/// against the renaming.
+fn diff_keys<K, L>(conf : &Conf, old : &[K], new : &[K]) -> Vec<DiffResult<L>>
+where K : AsRef<[u8]>, L : PartialEq + Clone + Default
+{
+    if conf.rename_re.is_none() && conf.numeric_tolerance.is_none() {
+        return diff_exact_keys(conf, old, new)
+    }
+    let old : Vec<Cow<[u8]>> = old.iter().map(|k| mask_values(conf, k.as_ref())).collect();
+    let new : Vec<Cow<[u8]>> = new.iter().map(|k| mask_values(conf, k.as_ref())).collect();
+    diff_exact_keys(conf, &old, &new)
+}
+
+// The values that are not compared as is (i.e. ones that are renamed,
+// then numbers) are masked out of the keys we diff.
+fn mask_values<'a>(conf : &Conf, key : &'a [u8]) -> Cow<'a, [u8]> {
+    let key = match conf.rename_re {
+        None => Cow::Borrowed (key),
+        Some (ref re) => rename::mask(re, key),
+    };
+    match conf.numeric_tolerance {
+        None => key,
+        Some (ref tol) => match tol.mask(&key) {
+            Cow::Borrowed (_) => key,
+            Cow::Owned (k) => Cow::Owned (k),
+        },
+    }
+}
+
+// Whether the values masked out of two keys (that are the same when
+// masked) are in agreement too.
+fn same_values(conf : &Conf, renaming : &mut Renaming, old : &[u8], new : &[u8]) -> bool {
+    if let Some (ref tol) = conf.numeric_tolerance {
+        let (old, new) = match conf.rename_re {
+            None => (Cow::Borrowed (old), Cow::Borrowed (new)),
+            Some (ref re) => (rename::mask(re, old), rename::mask(re, new)),
+        };
+        if !tol.within(&old, &new) {
+            return false
+        }
+    }
+    match conf.rename_re {
+        None => true,
+        Some (ref re) => renaming.extend(re, old, new),
+    }
+}
+
+/// Go over (a part of) the diff of the masked keys, in order, turning
+/// any common lines whose values disagree into a removed and an added
+/// line. The renaming carries over to the next part of the diff.
+fn check_values<K, L>(conf : &Conf, renaming : &mut Renaming, diff : Vec<DiffResult<L>>,
+                      old_keys : &[K], new_keys : &[K]) -> Vec<DiffResult<L>>
+where K : AsRef<[u8]>, L : PartialEq + Clone
+{
+    if conf.rename_re.is_none() && conf.numeric_tolerance.is_none() {
+        return diff
+    }
+    split_common(diff, |o, n| {
+        same_values(conf, renaming, old_keys[o].as_ref(), new_keys[n].as_ref())
+    })
+}
+
+// Turn the common lines (given by their indices in the old and new
+// file) for which keep doesn't hold into a removed and an added line.
+fn split_common<L, F>(diff : Vec<DiffResult<L>>, mut keep : F) -> Vec<DiffResult<L>>
+where L : PartialEq + Clone, F : FnMut(usize, usize) -> bool
+{
+    let mut ret = Vec::with_capacity(diff.len());
+    // Keep the removed lines of each run of changes ahead of the
+    // added ones, as the diff algorithms do.
+    let mut removed = vec![];
+    let mut added = vec![];
+    for d in diff {
+        match d {
+            DiffResult::Common (el) => {
+                let (o, n) = (el.old_index.unwrap(), el.new_index.unwrap());
+                if keep(o, n) {
+                    ret.append(&mut removed);
+                    ret.append(&mut added);
+                    ret.push(DiffResult::Common (el))
+                } else {
+                    removed.push(DiffResult::Removed (DiffElement {
+                        old_index : Some (o),
+                        new_index : None,
+                        data : el.data.clone(),
+                    }));
+                    added.push(DiffResult::Added (DiffElement {
+                        old_index : None,
+                        new_index : Some (n),
+                        data : el.data,
+                    }))
+                }
+            },
+            DiffResult::Removed (_) => removed.push(d),
+            DiffResult::Added (_) => added.push(d),
+        }
+    }
+    ret.extend(removed);
+    ret.extend(added);
+    ret
+}
+
+/// Identical runs of keys at the start and end of the files, which is
+/// where most of the lines end up when the files are mostly the same,
+/// never make it to the diff algorithm. The rest of the keys are
+/// interned, so that the diff algorithm only deals with integers.
+fn diff_exact_keys<K, L>(conf : &Conf, old : &[K], new : &[K]) -> Vec<DiffResult<L>>
+where K : AsRef<[u8]>, L : PartialEq + Clone + Default
+{
+    let same = |&(o, n) : &(&K, &K)| o.as_ref() == n.as_ref();
+    let prefix = old.iter().zip(new).take_while(same).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+        .take_while(same).count();
+    let old_mid = &old[prefix..(old.len() - suffix)];
+    let new_mid = &new[prefix..(new.len() - suffix)];
+
+    let common = |o, n| DiffResult::Common (DiffElement {
+        old_index : Some (o),
+        new_index : Some (n),
+        data : L::default(),
+    });
+    let mut ret = Vec::with_capacity(cmp::max(old.len(), new.len()));
+    ret.extend((0..prefix).map(|i| common(i, i)));
+    if !old_mid.is_empty() || !new_mid.is_empty() {
+        let mut ids = HashMap::new();
+        let old_ids = intern(&mut ids, old_mid);
+        let new_ids = intern(&mut ids, new_mid);
+        dprintln!(conf.debug, "Diffing {} and {} lines ({} distinct) after skipping {} + {}",
+                  old_ids.len(), new_ids.len(), ids.len(), prefix, suffix);
+        ret.extend(diff_lines(conf, &old_ids, &new_ids).into_iter()
+                   .map(|d| line_result(d, prefix, prefix)));
+    }
+    let old_suffix = old.len() - suffix;
+    let new_suffix = new.len() - suffix;
+    ret.extend((0..suffix).map(|i| common(old_suffix + i, new_suffix + i)));
+    ret
+}
+
+// The byte ranges of the line matched by the capturing groups of the RE.
+fn capture_ranges(conf : &Conf, re : &Re, line : &[u8]) -> Option<Vec<Range<usize>>> {
     if let Some (caps) = re.captures(line) {
         let mut ret = vec![];
         // Rightmost end of the matches we've seen so far.
@@ -93,45 +387,68 @@ fn sel_part_of_line(conf : &Conf, re : &Regex, line : &[u8]) -> Option<Vec<u8>>
         // skip matches that refer to a part of the line we've
         // already selected.
         let mut idx = 0;
-        for i in 1..caps.len() {
-            match caps.get(i) {
-                Some (m) => {
-                    if m.start() < idx {
+        for (i, cap) in caps.iter().enumerate().skip(1) {
+            match *cap {
+                Some (ref m) => {
+                    if m.start < idx {
                         // AFAIK, there's no way for matches to overlap but
                         // not be nested.
-                        assert!(m.end() <= idx);
+                        assert!(m.end <= idx);
                         continue
                     };
-                    idx = m.end();
+                    idx = m.end;
                     dprintln!(conf.debug, "Got match[{}]: `{}`", i,
-                              String::from_utf8(m.as_bytes().to_vec()).unwrap());
-                    ret.write_all(m.as_bytes()).unwrap()
+                              String::from_utf8(line[m.clone()].to_vec()).unwrap());
+                    ret.push(m.clone())
                 },
                 None => {
                     dprintln!(conf.debug, "No match[{}]", i)
                 }
             }
         }
-        // The user probably hasn't matched the trailing newline, but
-        // they may have requested that the matching part be printed,
-        // so add a newline here. XXX: this will interfere with
-        // final lines that end at EOF (i.e. not at a newline).
-        if ret.is_empty() || (ret[ret.len() - 1] != b'\n') {
-            ret.push(b'\n')
-        }
         Some (ret)
     } else {
         None
     }
 }
 
-fn omit_matching(line : &[u8], ignore_re : &Option<Regex>) -> Option<Vec<u8>> {
-    ignore_re.as_ref().map(|ignore_re| {
-        ignore_re.replace_all(&line, &b""[..]).into_owned()
+// The part of the line that's compared, given the ranges that were
+// selected from it.
+fn join_ranges(line : &[u8], ranges : &[Range<usize>]) -> Vec<u8> {
+    let mut ret = vec![];
+    for r in ranges {
+        ret.extend_from_slice(&line[r.clone()])
+    }
+    // The user probably hasn't matched the trailing newline, but
+    // they may have requested that the matching part be printed,
+    // so add a newline here. XXX: this will interfere with
+    // final lines that end at EOF (i.e. not at a newline).
+    if ret.is_empty() || (ret[ret.len() - 1] != b'\n') {
+        ret.push(b'\n')
+    }
+    ret
+}
+
+// Returns None if there was nothing to omit.
+fn omit_matching(line : &[u8], ignore_re : &Option<Re>) -> Option<Vec<u8>> {
+    ignore_re.as_ref().and_then(|ignore_re| {
+        let omitted = ignore_re.find_ranges(line);
+        if omitted.is_empty() {
+            return None
+        }
+        // The matches are in order and don't overlap.
+        let mut ret = vec![];
+        let mut off = 0;
+        for r in omitted {
+            ret.extend_from_slice(&line[off..r.start]);
+            off = r.end
+        }
+        ret.extend_from_slice(&line[off..]);
+        Some (ret)
    })
 }
 
-fn assert_capturing(re : &Regex, s : &str) {
+fn assert_capturing(re : &Re, s : &str) {
     // The whole RE counts as the first capture; we need a second one
     // or there's no point to using this RE.
     if re.captures_len() <= 1 {
@@ -140,116 +457,278 @@ fn assert_capturing(re : &Regex, s : &str) {
     }
 }
 
-trait ReSelector {
-    fn sel(&self, &Conf, &[u8]) -> Option<Vec<u8>>;
+// A line was matched by more than one RE (these ones), when the user
+// has asked for that to be an error.
+#[derive(Debug)]
+struct AmbiguousMatch (Vec<String>);
+
+impl AmbiguousMatch {
+    fn report(&self, path : &Path, lineno : usize, line : &[u8]) -> ! {
+        // Other threads might run into the same problem; hold on to
+        // stderr so that the messages don't get interleaved.
+        let stderr = io::stderr();
+        let mut err = stderr.lock();
+        writeln!(err, "{}:{}: line is matched by more than one regular expression:",
+                 path.display(), lineno).unwrap();
+        err.write_all(b"`").unwrap();
+        err.write_all(line).unwrap();
+        writeln!(err, "` is matched by:").unwrap();
+        for re in &self.0 {
+            writeln!(err, "{}", re).unwrap();
+        }
+        exit(2)
+    }
+}
+
+// The parts of a line that were selected by the REs and, out of those,
+// the parts to be dropped by the ignore REs that were scoped to them.
+struct Selected {
+    ranges : Vec<Range<usize>>,
+    ignored : Vec<Range<usize>>,
+}
+
+type SelectedRanges = Result<Option<Selected>, AmbiguousMatch>;
+
+// What's left of the ranges after cutting out the ignored ones.
+fn cut_ranges(ranges : &[Range<usize>], ignored : &[Range<usize>]) -> Vec<Range<usize>> {
+    let mut ret = ranges.to_vec();
+    for ig in ignored {
+        ret = ret.into_iter().flat_map(|r| {
+            let mut left = vec![];
+            if r.start < ig.start {
+                left.push(r.start..cmp::min(r.end, ig.start))
+            }
+            if ig.end < r.end {
+                left.push(cmp::max(r.start, ig.end)..r.end)
+            }
+            left
+        }).collect()
+    }
+    ret
+}
+
+// The same bytes as the ranges, as non-empty, non-overlapping ranges
+// in the order they appear in the line.
+fn merge_ranges(mut ranges : Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.retain(|r| r.start < r.end);
+    ranges.sort_by_key(|r| r.start);
+    let mut ret : Vec<Range<usize>> = vec![];
+    for r in ranges {
+        match ret.last_mut() {
+            Some (ref mut last) if r.start <= last.end => {
+                last.end = cmp::max(last.end, r.end);
+                continue
+            },
+            _ => (),
+        }
+        ret.push(r)
+    }
+    ret
+}
+
+// Lines are selected in parallel, hence the Sync.
+trait ReSelector : Sync {
+    // The selected ranges of the line, None if the line wasn't matched.
+    fn ranges(&self, &Conf, &[u8]) -> SelectedRanges;
+    fn sel(&self, conf : &Conf, line : &[u8]) -> Result<Option<Vec<u8>>, AmbiguousMatch> {
+        self.ranges(conf, line).map(|s| s.map(|s| {
+            join_ranges(line, &cut_ranges(&s.ranges, &s.ignored))
+        }))
+    }
 }
 
 struct NoneRe;
 
 impl ReSelector for NoneRe {
-    fn sel(&self, _ : &Conf, _ : &[u8]) -> Option<Vec<u8>> {
-        None
+    fn ranges(&self, _ : &Conf, _ : &[u8]) -> SelectedRanges {
+        Ok (None)
     }
 }
 
-struct SingleRe(Regex);
+fn build_re(engine : RegexEngine, s : &str) -> Re {
+    match Re::build(engine, s) {
+        Err (err) => {
+            eprintln!("Could not compile regular expression `{}`: {}", s, err);
+            exit(2)
+        },
+        Ok (re) => re,
+    }
+}
 
-impl SingleRe {
-    fn build(s : &str) -> SingleRe {
-        // Note: Our lines contain the EOL character. Use multi-line mode, so that
+// One of the REs given with -r, along with the ignore REs that only
+// apply to the parts of the lines it selects.
+struct ScopedRe {
+    re : Re,
+    ignores : Vec<Re>,
+}
+
+impl ScopedRe {
+    fn build(engine : RegexEngine, s : &str, ignores : &[&str]) -> ScopedRe {
+        // Note: Our lines contain the EOL character. Re uses multi-line mode, so that
         // $ can match the EOL and the RE will still work if the user does ^foo$.
-        match RegexBuilder::new(s).multi_line(true).build() {
-            Ok (re) => {
-                assert_capturing(&re, s);
-                SingleRe(re)
-            },
-            Err (err) => {
-                eprintln!("Could not compile regular expression `{}`: {}",
-                          s, err);
-                exit(2)
-            }
+        let re = build_re(engine, s);
+        assert_capturing(&re, s);
+        ScopedRe {
+            re,
+            ignores : ignores.iter().map(|s| build_re(engine, s)).collect(),
         }
     }
+
+    fn select(&self, conf : &Conf, line : &[u8]) -> Option<Selected> {
+        capture_ranges(conf, &self.re, line).map(|ranges| {
+            let ignored = if self.ignores.is_empty() {
+                vec![]
+            } else {
+                let key = join_ranges(line, &ranges);
+                self.ignores.iter().flat_map(|re| {
+                    re.find_ranges(&key).into_iter().filter(|m| m.start < m.end)
+                        .flat_map(|m| key_to_line(&ranges, m))
+                        .collect::<Vec<_>>()
+                }).collect()
+            };
+            Selected {
+                ranges,
+                ignored,
+            }
+        })
+    }
+}
+
+// The scoped ignores of the RE at each index.
+fn scoped_ignores<'a>(idx : usize, scoped : &[(usize, &'a str)]) -> Vec<&'a str> {
+    scoped.iter().filter(|&&(i, _)| i == idx).map(|&(_, s)| s).collect()
+}
+
+struct SingleRe(ScopedRe);
+
+impl SingleRe {
+    fn build(engine : RegexEngine, s : &str, scoped : &[(usize, &str)]) -> SingleRe {
+        SingleRe(ScopedRe::build(engine, s, &scoped_ignores(0, scoped)))
+    }
 }
 
 impl ReSelector for SingleRe {
-    fn sel(&self, conf : &Conf, line : &[u8]) -> Option<Vec<u8>> {
-        sel_part_of_line(conf, &self.0, line)
+    fn ranges(&self, conf : &Conf, line : &[u8]) -> SelectedRanges {
+        Ok (self.0.select(conf, line))
     }
 }
 
 struct MultiRe {
-    multi : RegexSet,
-    regexes : Vec<Regex>,
+    // None if any of the REs uses the fancy engine; those need to
+    // be tried one by one.
+    multi : Option<RegexSet>,
+    regexes : Vec<ScopedRe>,
 }
 
 impl MultiRe {
-    fn build<I, S>(strs : I) -> MultiRe
+    fn build<I, S>(engine : RegexEngine, strs : I, scoped : &[(usize, &str)]) -> MultiRe
     where S : AsRef<str>,
-    I : IntoIterator<Item = S> + Clone
+    I : IntoIterator<Item = S>
     {
         // Compile the individual REs first, so that we can tell
         // the user which RE had an error.
-        let regexes =
-            strs.clone().into_iter().map(|s| {
-                match RegexBuilder::new(s.as_ref()).multi_line(true).build() {
-                    Ok (re) => {
-                        assert_capturing(&re, s.as_ref());
-                        re
-                    },
-                    Err (err) => {
-                        eprintln!("Could not compile regular expression `{}`: {}",
-                                  s.as_ref(), err);
-                        exit(2)
-                    },
-                }
+        let regexes : Vec<ScopedRe> =
+            strs.into_iter().enumerate().map(|(i, s)| {
+                ScopedRe::build(engine, s.as_ref(), &scoped_ignores(i, scoped))
             }).collect();
-        let multi = match RegexSetBuilder::new(strs).multi_line(true).build() {
-            Ok (set) => set,
-            Err (err) => {
-                eprintln!("Could not build regular expression set: {}", err);
-                exit(2)
-            },
-        };
+        let plain : Option<Vec<&str>> = regexes.iter().map(|r| {
+            r.re.plain().map(|re| re.as_str())
+        }).collect();
+        let multi = plain.map(|plain| {
+            match RegexSetBuilder::new(plain).multi_line(true).build() {
+                Ok (set) => set,
+                Err (err) => {
+                    eprintln!("Could not build regular expression set: {}", err);
+                    exit(2)
+                },
+            }
+        });
         MultiRe {
             multi,
             regexes,
         }
     }
+
+    fn matched(&self, conf : &Conf, idx : usize, line : &[u8]) -> Selected {
+        match self.regexes[idx].select(conf, line) {
+            Some (m) => m,
+            None => panic!("RegexSet claimed a match, but the RE disagrees")
+        }
+    }
 }
 
 impl ReSelector for MultiRe {
-    fn sel(&self, conf : &Conf, line : &[u8]) -> Option<Vec<u8>> {
-        let mut matches = self.multi.matches(line).into_iter();
-        match matches.next() {
-            None => None,
-            Some (single) => {
-                match matches.next() {
-                    None => {
-                        let re = &self.regexes[single];
-                        match sel_part_of_line(conf, re, line) {
-                            m @ Some (_) => m,
-                            None => panic!("RegexSet claimed a match, but the RE disagrees")
-                        }
-                    },
-                    Some (_) => {
-                        eprintln!("Line is matched by more than \
-                                   one regular expression:");
-                        io::stderr().write_all(b"`").unwrap();
-                        io::stderr().write_all(line).unwrap();
-                        eprintln!("` is matched by:");
-                        for re in &self.regexes {
-                            eprintln!("{}", re);
-                        }
-                        exit(2)
+    fn ranges(&self, conf : &Conf, line : &[u8]) -> SelectedRanges {
+        // These are in the order the REs were given in.
+        let matches : Vec<usize> = match self.multi {
+            Some (ref multi) => multi.matches(line).into_iter().collect(),
+            None => (0..self.regexes.len()).filter(|&idx| {
+                self.regexes[idx].re.is_match(line)
+            }).collect(),
+        };
+        if matches.len() < 2 {
+            return Ok (matches.first().map(|&idx| self.matched(conf, idx, line)))
+        }
+        match conf.multi_match {
+            MultiMatch::Error => {
+                Err (AmbiguousMatch (matches.iter().map(|&idx| {
+                    self.regexes[idx].re.as_str().to_string()
+                }).collect()))
+            },
+            MultiMatch::First => Ok (Some (self.matched(conf, matches[0], line))),
+            MultiMatch::Longest => {
+                let len = |sel : &Selected| -> usize {
+                    sel.ranges.iter().map(|r| r.end - r.start).sum()
+                };
+                let mut best = self.matched(conf, matches[0], line);
+                for &idx in &matches[1..] {
+                    let sel = self.matched(conf, idx, line);
+                    if len(&sel) > len(&best) {
+                        best = sel
                     }
                 }
-            }
+                Ok (Some (best))
+            },
+            MultiMatch::AllConcat => {
+                // The scoped ignores of each RE only apply to what that
+                // RE selected; parts that another RE also selected are
+                // kept, unless its own ignores drop them too.
+                let mut ranges = vec![];
+                let mut kept = vec![];
+                for &idx in &matches {
+                    let sel = self.matched(conf, idx, line);
+                    kept.extend(cut_ranges(&sel.ranges, &sel.ignored));
+                    ranges.extend(sel.ranges)
+                }
+                let ranges = merge_ranges(ranges);
+                let ignored = cut_ranges(&ranges, &merge_ranges(kept));
+                Ok (Some (Selected {
+                    ranges,
+                    ignored,
+                }))
+            },
         }
     }
 }
 
-fn build_re_selector<I, S>(re_strs : I) -> Box<ReSelector>
+// The REs of a profile, which only get to select the lines that none of
+// the REs given on the command line matched.
+struct FallbackRe {
+    first : Box<ReSelector>,
+    fallback : Box<ReSelector>,
+}
+
+impl ReSelector for FallbackRe {
+    fn ranges(&self, conf : &Conf, line : &[u8]) -> SelectedRanges {
+        match self.first.ranges(conf, line)? {
+            Some (sel) => Ok (Some (sel)),
+            None => self.fallback.ranges(conf, line),
+        }
+    }
+}
+
+fn build_re_selector<I, S>(engine : RegexEngine, re_strs : I,
+                          scoped : &[(usize, &str)]) -> Box<ReSelector>
 where
     S : AsRef<str>,
     I : IntoIterator<Item=S> + Clone
@@ -265,25 +744,324 @@ where
     match len {
         1 => {
             let s = re_strs.into_iter().next().unwrap();
-            Box::new(SingleRe::build(s.as_ref()))
+            Box::new(SingleRe::build(engine, s.as_ref(), scoped))
+        },
+        _ => Box::new(MultiRe::build(engine, re_strs, scoped)),
+    }
+}
+
+// Keys that no rule applies to are not copied.
+fn substitute<'a>(substitutions : &[Substitution], key : Cow<'a, [u8]>) -> Cow<'a, [u8]> {
+    substitutions.iter().fold(key, |key, subst| {
+        let replaced = match subst.apply(&key) {
+            Cow::Borrowed (_) => None,
+            Cow::Owned (s) => Some (s),
+        };
+        replaced.map_or(key, Cow::Owned)
+    })
+}
+
+// Lines that are compared in whole are not copied.
+fn extract_re_matches<'a>(conf : &Conf, re : &ReSelector, ignore_re : &Option<Re>,
+                          line : &'a [u8]) -> Result<Cow<'a, [u8]>, AmbiguousMatch> {
+    let key = match re.sel(conf, line)? {
+        None => omit_matching(line, ignore_re).map_or(Cow::Borrowed (line), Cow::Owned),
+        Some (s) => Cow::Owned (omit_matching(&s, ignore_re).unwrap_or(s)),
+    };
+    Ok (substitute(&conf.substitutions, key))
+}
+
+// Selection is done in parallel; the results are still in the same
+// order as the lines. The first of the lines is line `off` of the file.
+fn pick_lines<'a, L>(conf : &Conf, mre : &ReSelector, ignore_re : &Option<Re>,
+                     path : &Path, off : usize, lines : &'a [L]) -> Vec<Cow<'a, [u8]>>
+where L : AsRef<[u8]> + Sync
+{
+    lines.par_iter().enumerate().map(|(i, l)| {
+        match extract_re_matches(conf, mre, ignore_re, l.as_ref()) {
+            Ok (key) => key,
+            Err (amb) => amb.report(path, off + i + 1, l.as_ref()),
+        }
+    }).collect()
+}
+
+// Convert a range of the key of a line, as built by join_ranges, to
+// the corresponding ranges of the line itself.
+fn key_to_line(ranges : &[Range<usize>], r : Range<usize>) -> Vec<Range<usize>> {
+    let mut ret = vec![];
+    let mut off = 0;
+    for sel in ranges {
+        let len = sel.end - sel.start;
+        let start = cmp::max(r.start, off);
+        let end = cmp::min(r.end, off + len);
+        if start < end {
+            ret.push((sel.start + start - off)..(sel.start + end - off))
+        }
+        off += len
+    }
+    ret
+}
+
+// What became of the bytes of a line when picking its key: the ranges
+// that were selected by the REs (None if the line was compared in
+// whole) and the ranges that were then dropped by the ignore RE.
+struct LineSpans {
+    selected : Option<Vec<Range<usize>>>,
+    ignored : Vec<Range<usize>>,
+}
+
+// The regular expressions given on the command line. These are compiled
+// once and shared by all the pairs of files we end up comparing.
+struct Selection {
+    re : Option<Box<ReSelector>>,
+    ignore_re : Option<Re>,
+}
+
+impl Selection {
+    // Each of the scoped ignores applies to the RE at the given index.
+    fn build<'a, I>(conf : &Conf, re : Option<I>, ignore_re : Option<&str>,
+                    scoped : &[(usize, &str)]) -> Selection
+    where
+        I : IntoIterator<Item = &'a str> + Clone
+    {
+        Selection {
+            re : re.map(|re| build_re_selector(conf.regex_engine, re, scoped)),
+            ignore_re : ignore_re.map(|s| build_re(conf.regex_engine, s)),
+        }
+    }
+
+    // Add REs that are only tried on the lines the existing ones don't match.
+    fn with_fallback<'a, I>(self, conf : &Conf, re : I) -> Selection
+    where
+        I : IntoIterator<Item = &'a str> + Clone
+    {
+        let fallback = build_re_selector(conf.regex_engine, re, &[]);
+        Selection {
+            re : Some (match self.re {
+                Some (first) => Box::new(FallbackRe {
+                    first,
+                    fallback,
+                }),
+                None => fallback,
+            }),
+            ..self
+        }
+    }
+
+    fn is_empty(&self, conf : &Conf) -> bool {
+        self.re.is_none() && self.ignore_re.is_none() && conf.substitutions.is_empty()
+    }
+
+    // The parts of the lines that take part in the comparison. The
+    // path and offset of the lines are for reporting errors.
+    fn pick<'a, L>(&self, conf : &Conf, path : &Path, off : usize,
+                   lines : &'a [L]) -> Vec<Cow<'a, [u8]>>
+    where L : AsRef<[u8]> + Sync
+    {
+        match self.re {
+            Some (ref re) => pick_lines(conf, &**re, &self.ignore_re, path, off, lines),
+            None => pick_lines(conf, &NoneRe, &self.ignore_re, path, off, lines),
+        }
+    }
+
+    // Same as pick, but for a single line and keeping track of where
+    // the parts of the key came from.
+    fn spans(&self, conf : &Conf, line : &[u8]) -> LineSpans {
+        // Any ambiguous matches were reported when picking the keys.
+        let selected = match self.re {
+            Some (ref re) => re.ranges(conf, line).unwrap_or(None),
+            None => None,
+        };
+        let (selected, mut ignored) = match selected {
+            None => (None, vec![]),
+            Some (sel) => (Some (sel.ranges), sel.ignored),
+        };
+        if let Some (ref ignore_re) = self.ignore_re {
+            match selected {
+                None => {
+                    ignored.extend(ignore_re.find_ranges(line).into_iter()
+                                   .filter(|m| m.start < m.end))
+                },
+                Some (ref ranges) => {
+                    // The scoped ignores have already had their pick.
+                    let ranges = cut_ranges(ranges, &ignored);
+                    let key = join_ranges(line, &ranges);
+                    let global : Vec<Range<usize>> = ignore_re.find_ranges(&key).into_iter()
+                        .flat_map(|m| key_to_line(&ranges, m)).collect();
+                    ignored.extend(global)
+                },
+            }
+        }
+        LineSpans {
+            selected,
+            ignored,
+        }
+    }
+}
+
+fn write_diff<K : AsRef<[u8]>>(out : &mut Write, conf : &Conf, sel : &Selection,
+                               old : &Side<&[u8], K>, new : &Side<&[u8], K>,
+                               diff : Vec<DiffResult<&[u8]>>) -> io::Result<i32> {
+    if !exist_differences(&diff) {
+        return Ok (0); // Exit w/o producing any output
+    }
+    match conf.output_format {
+        OutputFormat::Json => {
+            for_each_hunk(conf, diff, &mut |hunk : Option<&Hunk<&[u8]>>| {
+                match hunk {
+                    None => Ok (()),
+                    Some (hunk) => json::write_hunk(out, conf, hunk, old, new),
+                }
+            })?;
+            Ok (1)
         },
-        _ => Box::new(MultiRe::build(re_strs)),
+        OutputFormat::Html => {
+            html::file_begin(out, old.path, new.path)?;
+            for_each_hunk(conf, diff, &mut |hunk : Option<&Hunk<&[u8]>>| {
+                match hunk {
+                    None => Ok (()),
+                    Some (hunk) => html::write_hunk(out, conf, sel, hunk, old, new),
+                }
+            })?;
+            html::file_end(out)?;
+            Ok (1)
+        },
+        _ => {
+            file_headers(out, conf, old.path, new.path)?;
+            // The other formats don't display pairs.
+            let pair = conf.pair_changes && conf.output_format == OutputFormat::Unified;
+            if !pair && conf.section_re.is_none() {
+                return display_diff_hunked::<&[u8]>(out, conf, old.lines, new.lines, diff)
+            }
+            let mut sections = Sections::new();
+            for_each_hunk(conf, diff, &mut |hunk : Option<&Hunk<&[u8]>>| {
+                match hunk {
+                    None => Ok (()),
+                    Some (hunk) => {
+                        let mut hunk = hunk.clone();
+                        sections.scan(conf, old.lines, 0, hunk.old_start);
+                        hunk.section = sections.last();
+                        if pair {
+                            hunk.pairs = pairing::pair_changes(conf, &hunk.items,
+                                                               old.keys, new.keys);
+                        }
+                        hunk.do_write(conf, old.lines, new.lines, out)
+                    },
+                }
+            })?;
+            Ok (1)
+        },
+    }
+}
+
+fn diff_file_pair(out : &mut Write, conf : &Conf, sel : &Selection,
+                  old : &Path, new : &Path) -> io::Result<i32> {
+    if let Some (window) = conf.stream_window {
+        return stream::diff_streaming(out, conf, sel, old, new, window)
+    }
+    let old_input = read_input(old)?;
+    let new_input = read_input(new)?;
+    let old_lines = split_lines(&old_input);
+    let new_lines = split_lines(&new_input);
+
+    if sel.is_empty(conf) {
+        let diff = diff_keys(conf, &old_lines, &new_lines);
+        let diff = check_values(conf, &mut Renaming::new(), diff, &old_lines, &new_lines);
+        return write_diff(out, conf, sel, &Side::new(old, &old_lines, &old_lines),
+                          &Side::new(new, &new_lines, &new_lines), diff)
+    }
+    let (pick_old, pick_new) = rayon::join(|| sel.pick(conf, old, 0, &old_lines),
+                                           || sel.pick(conf, new, 0, &new_lines));
+    let diff = diff_keys(conf, &pick_old, &pick_new);
+    let diff = check_values(conf, &mut Renaming::new(), diff, &pick_old, &pick_new);
+    if conf.display_selected {
+        // If the user requested that only the matching parts
+        // be produced as output, reference the those parts
+        // as the lines of the original files
+        let sel_old : Vec<&[u8]> = pick_old.iter().map(|k| &k[..]).collect();
+        let sel_new : Vec<&[u8]> = pick_new.iter().map(|k| &k[..]).collect();
+        write_diff(out, conf, sel, &Side::new(old, &sel_old, &pick_old),
+                   &Side::new(new, &sel_new, &pick_new), diff)
+    } else {
+        write_diff(out, conf, sel, &Side::new(old, &old_lines, &pick_old),
+                   &Side::new(new, &new_lines, &pick_new), diff)
+    }
+}
+
+// When given a directory and a file, diff compares the file against
+// the file with the same name in the directory.
+fn path_in_dir(dir : &Path, file : &Path) -> io::Result<PathBuf> {
+    match file.file_name() {
+        Some (name) => Ok (dir.join(name)),
+        None => Err (io::Error::new(io::ErrorKind::InvalidInput,
+                                    format!("Cannot compare directory `{}` to `{}`",
+                                            dir.display(), file.display()))),
     }
 }
 
-fn extract_re_matches(conf : &Conf, re : &ReSelector,
-                      ignore_re : &Option<Regex>, line : &[u8]) -> Vec<u8> {
-    match re.sel(conf, &line) {
-        None => omit_matching(line, &ignore_re).unwrap_or_else(|| line.to_vec()),
-        Some (s) => omit_matching(&s, &ignore_re).unwrap_or(s),
+// Writes out a line ahead of the first output, if any.
+struct Announced<'a> {
+    out : &'a mut Write,
+    line : Option<Vec<u8>>,
+}
+
+impl<'a> Write for Announced<'a> {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        if let Some (line) = self.line.take() {
+            self.out.write_all(&line)?
+        }
+        self.out.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
     }
 }
 
-fn pick_lines(conf : &Conf, mre : &ReSelector, ignore_re : &Option<Regex>,
-              lines : &[Vec<u8>]) -> Vec<Vec<u8>> {
-    lines.iter().map(|l| extract_re_matches(conf, mre, ignore_re, l)).collect()
+// The side by side format has no file headers, so, like diff -r does,
+// precede the output for each pair of files with a line naming them.
+fn diff_line(old : &Path, new : &Path) -> Vec<u8> {
+    let mut line = b"diff ".to_vec();
+    line.extend(old.as_os_str().to_os_string().into_vec());
+    line.push(b' ');
+    line.extend(new.as_os_str().to_os_string().into_vec());
+    line.push(b'\n');
+    line
 }
 
+fn diff_paths(out : &mut Write, conf : &Conf, sel : &Selection,
+              old : &Path, new : &Path) -> io::Result<i32> {
+    // Like diff, consider the standard input identical to itself, instead
+    // of reading it twice (and finding it empty the second time).
+    if is_stdin(old) && is_stdin(new) {
+        return Ok (0)
+    }
+    if (is_stdin(old) && new.is_dir()) || (old.is_dir() && is_stdin(new)) {
+        return Err (io::Error::new(io::ErrorKind::InvalidInput,
+                                   "Cannot compare `-` to a directory"))
+    }
+    match (old.is_dir(), new.is_dir()) {
+        (true, true) => {
+            let mut diff_pair = |out : &mut Write, old : &Path, new : &Path| {
+                if conf.output_format != OutputFormat::SideBySide {
+                    return diff_file_pair(out, conf, sel, old, new)
+                }
+                let mut out = Announced {
+                    out,
+                    line : Some (diff_line(old, new)),
+                };
+                diff_file_pair(&mut out, conf, sel, old, new)
+            };
+            dirdiff::diff_dirs(out, conf, &mut diff_pair, old, new)
+        },
+        (true, false) => diff_file_pair(out, conf, sel, &path_in_dir(old, new)?, new),
+        (false, true) => diff_file_pair(out, conf, sel, old, &path_in_dir(new, old)?),
+        (false, false) => diff_file_pair(out, conf, sel, old, new),
+    }
+}
+
+// main builds the Selection itself, to add scoped ignores and profiles.
+#[cfg(test)]
 fn diff_files<'a, I>(out : &mut Write, conf : &Conf,
                  re : Option<I>,
                  ignore_re : Option<&str>,
@@ -291,43 +1069,20 @@ fn diff_files<'a, I>(out : &mut Write, conf : &Conf,
 where
     I : IntoIterator<Item = &'a str> + Clone
 {
-    let mut old_lines = read_lines(old)?;
-    let mut new_lines = read_lines(new)?;
-
-    let ignore_re = ignore_re.and_then(|s| {
-        match RegexBuilder::new(s).multi_line(true).build() {
-            Err (err) => {
-                eprintln!("Could not compile regular expression `{}`: {}", s, err);
-                exit(2)
-            },
-            Ok (re) => Some (re),
-        }
-    });
+    diff_selection(out, conf, &Selection::build(conf, re, ignore_re, &[]), old, new)
+}
 
-    let diff : Vec<DiffResult<Vec<u8>>> = match (re, &ignore_re) {
-        (None, &None) => lcs_diff::diff(&old_lines, &new_lines),
-        (re, _) => {
-            let mre : Box<ReSelector> = re.map(build_re_selector)
-                .unwrap_or_else(|| Box::new(NoneRe));
-            let pick_old = pick_lines(conf, &*mre, &ignore_re, &old_lines);
-            let pick_new = pick_lines(conf, &*mre, &ignore_re, &new_lines);
-            let d = lcs_diff::diff(&pick_old, &pick_new);
-            if conf.display_selected {
-                // If the user requested that only the matching parts
-                // be produced as output, reference the those parts
-                // as the lines of the original files
-                old_lines = pick_old;
-                new_lines = pick_new;
-            }
-            d
-        },
-      };
-    if !exist_differences(&diff) {
-        return Ok (0); // Exit w/o producing any output
+// Same as diff_files, with the REs already compiled.
+fn diff_selection(out : &mut Write, conf : &Conf, sel : &Selection,
+                  old : &Path, new : &Path) -> io::Result<i32> {
+    if conf.output_format != OutputFormat::Html {
+        return diff_paths(out, conf, sel, old, new)
     }
-    file_header(out, b"---", old)?;
-    file_header(out, b"+++", new)?;
-    display_diff_hunked::<Vec<u8>>(out, conf, &old_lines, &new_lines, diff)
+    // The whole run goes into a single page.
+    html::begin(out, old, new)?;
+    let ecode = diff_paths(out, conf, sel, old, new)?;
+    html::end(out)?;
+    Ok (ecode)
 }
 
 fn parse_usize(s : &str) -> usize {
@@ -340,8 +1095,79 @@ fn parse_usize(s : &str) -> usize {
     }
 }
 
-fn main() {
-    let mut app = App::new("subdiff")
+fn validate_tokenization(s : String) -> Result<(), String> {
+    ContextLineTokenization::validate(&s)
+}
+
+fn validate_width(s : String) -> Result<(), String> {
+    match usize::from_str(&s) {
+        Ok (width) if width >= sidebyside::MIN_WIDTH => Ok (()),
+        Ok (_) => Err (format!("needs to be at least {} columns, to fit the gutter and both sides",
+                               sidebyside::MIN_WIDTH)),
+        Err (err) => Err (err.to_string()),
+    }
+}
+
+fn parse_tokenization(s : &str) -> ContextLineTokenization {
+    match ContextLineTokenization::new(s) {
+        Ok (tokenization) => tokenization,
+        Err (err) => {
+            eprintln!("Unsupported context tokenization `{}`: {}", s, err);
+            exit(2)
+        },
+    }
+}
+
+fn parse_substitution(s : &str) -> Substitution {
+    match Substitution::parse(s) {
+        Ok (subst) => subst,
+        Err (err) => {
+            eprintln!("{}", err);
+            exit(2)
+        },
+    }
+}
+
+fn parse_tolerance(s : &str) -> NumericTolerance {
+    match NumericTolerance::parse(s) {
+        Ok (tol) => tol,
+        Err (err) => {
+            eprintln!("{}", err);
+            exit(2)
+        },
+    }
+}
+
+fn parse_cc_class(s : &str) -> CcClass {
+    let (name, re) = match s.find('=') {
+        Some (i) => (&s[..i], Some (&s[i + 1..])),
+        None => (s, None),
+    };
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+        eprintln!("Invalid character class name `{}`", name);
+        exit(2)
+    }
+    match re {
+        Some (re) => match CcClass::new(name, re) {
+            Ok (cl) => cl,
+            Err (err) => {
+                eprintln!("Could not compile regular expression `{}`: {}", re, err);
+                exit(2)
+            },
+        },
+        None => match CcClass::builtin(name) {
+            Some (cl) => cl,
+            None => {
+                eprintln!("Unknown character class `{}` (predefined classes: {})",
+                          name, CcClass::builtin_names().join(", "));
+                exit(2)
+            },
+        },
+    }
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    let app = App::new("subdiff")
         .version("0.1")
         .arg(Arg::with_name("context")
              .short("c")
@@ -349,13 +1175,13 @@ fn main() {
              .help("Number of displayed context lines")
              .default_value("3"))
         .arg(Arg::with_name("old")
-             .required(true)
+             .required_unless("print_auto_ignore")
              .index(1)
-             .help("OLD file"))
+             .help("OLD file or directory (`-` for stdin)"))
         .arg(Arg::with_name("new")
-             .required(true)
+             .required_unless("print_auto_ignore")
              .index(2)
-             .help("NEW file"))
+             .help("NEW file or directory (`-` for stdin)"))
         .arg(Arg::with_name("common_re")
              .required(false)
              .short("r")
@@ -373,6 +1199,66 @@ fn main() {
              .number_of_values(1)
              .value_name("RE")
              .help("Ignore parts of lines matched by this regexp"))
+        .arg(Arg::with_name("substitute")
+             .required(false)
+             .short("s")
+             .long("substitute")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("s/RE/REPL/[g]")
+             .help("Rewrite the compared parts of lines, before comparing them"))
+        .arg(Arg::with_name("auto_ignore")
+             .required(false)
+             .long("auto-ignore")
+             .takes_value(false)
+             .help("Replace timestamps, addresses, UUIDs, PIDs, IPs and durations before comparing"))
+        .arg(Arg::with_name("print_auto_ignore")
+             .required(false)
+             .long("print-auto-ignore")
+             .takes_value(false)
+             .help("Print the substitutions of --auto-ignore (in the syntax of -s) and exit"))
+        .arg(Arg::with_name("rename_re")
+             .required(false)
+             .long("rename")
+             .takes_value(true)
+             .value_name("RE")
+             .help("Compare the values matched by RE up to a consistent renaming"))
+        .arg(Arg::with_name("numeric_tolerance")
+             .required(false)
+             .long("numeric-tolerance")
+             .takes_value(true)
+             .value_name("[RE:]TOL")
+             .help("Compare numbers within TOL (N% or abs:N) of each other as equal"))
+        .arg(Arg::with_name("multi_match")
+             .required(false)
+             .long("multi-match")
+             .takes_value(true)
+             .help("What to do when a line is matched by more than one regexp")
+             .possible_values(&conf::MultiMatch::allowed_values())
+             .default_value("error"))
+        .arg(Arg::with_name("regex_engine")
+             .required(false)
+             .long("regex-engine")
+             .takes_value(true)
+             .help("Engine for the regexps of -r, -i and -I (fancy supports lookaround)")
+             .possible_values(&conf::RegexEngine::allowed_values())
+             .default_value("regex"))
+        .arg(Arg::with_name("profile")
+             .required(false)
+             .long("profile")
+             .takes_value(true)
+             .value_name("NAME")
+             .help("Use the regexps and context settings of a named profile"))
+        .arg(Arg::with_name("scoped_ignore")
+             .required(false)
+             .short("I")
+             .long("scoped-ignore")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("RE")
+             .help("Ignore parts of lines matched by RE, if selected by the preceding -r"))
         .arg(Arg::with_name("context_format")
              .required(false)
              .long("context-format")
@@ -380,18 +1266,102 @@ fn main() {
              .help("Format for displayed context lines")
              .possible_values(&conf::ContextLineFormat::allowed_values())
              .default_value("wdiff"))
+        .arg(Arg::with_name("cc_class")
+             .required(false)
+             .long("cc-class")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("NAME[=RE]")
+             .help("Summarize text matched by RE as \\NAME in the cc context formats"))
         .arg(Arg::with_name("context_tokenization")
              .required(false)
              .short("t")
              .long("context-tokenization")
              .help("Tokenization of context lines for diffing")
-             .possible_values(&conf::ContextLineTokenization::allowed_values())
+             .validator(validate_tokenization)
              .default_value("word"))
+        .arg(Arg::with_name("diff_algorithm")
+             .required(false)
+             .long("diff-algorithm")
+             .takes_value(true)
+             .help("Algorithm used for comparing lines")
+             .possible_values(&conf::DiffAlgorithm::allowed_values())
+             .default_value("myers"))
+        .arg(Arg::with_name("section_re")
+             .required(false)
+             .short("F")
+             .long("show-function-line")
+             .takes_value(true)
+             .value_name("RE")
+             .help("Show the most recent line matching RE in the hunk headers"))
+        .arg(Arg::with_name("show_c_function")
+             .required(false)
+             .short("p")
+             .long("show-c-function")
+             .takes_value(false)
+             .conflicts_with("section_re")
+             .help("Show which C function each change is in"))
+        .arg(Arg::with_name("pair_changes")
+             .required(false)
+             .long("pair-changes")
+             .takes_value(false)
+             .help("Display similar removed and added lines next to each other"))
         .arg(Arg::with_name("mark_changed_context")
              .required(false)
              .long("mark-changed-context")
              .takes_value(false)
              .help("Mark changed context lines with '!'"))
+        .arg(Arg::with_name("stream")
+             .required(false)
+             .long("stream")
+             .takes_value(false)
+             .help("Diff the inputs a window at a time, w/o reading them in whole"))
+        .arg(Arg::with_name("stream_window")
+             .required(false)
+             .long("stream-window")
+             .takes_value(true)
+             .value_name("N")
+             .help("Number of lines per window when streaming")
+             .default_value("1000"))
+        .arg(Arg::with_name("format")
+             .required(false)
+             .long("format")
+             .takes_value(true)
+             .value_name("FORMAT")
+             .help("Output format")
+             .possible_values(&conf::OutputFormat::allowed_values())
+             .default_value("unified"))
+        .arg(Arg::with_name("side_by_side")
+             .required(false)
+             .short("y")
+             .long("side-by-side")
+             .takes_value(false)
+             .help("Output in two columns (same as --format=side-by-side)"))
+        .arg(Arg::with_name("width")
+             .required(false)
+             .short("W")
+             .long("width")
+             .takes_value(true)
+             .value_name("N")
+             .help("Output at most N columns when displaying side by side")
+             .validator(validate_width)
+             .default_value("130"))
+        .arg(Arg::with_name("color")
+             .required(false)
+             .long("color")
+             .takes_value(true)
+             .value_name("WHEN")
+             .help("Color the output")
+             .possible_values(&conf::ColorWhen::allowed_values())
+             .default_value("auto"))
+        .arg(Arg::with_name("jobs")
+             .required(false)
+             .short("j")
+             .long("jobs")
+             .takes_value(true)
+             .value_name("N")
+             .help("Number of threads to use for selecting lines (default: one per CPU)"))
         .arg(Arg::with_name("display_selected")
              .required(false)
              .long("display-selected")
@@ -399,39 +1369,198 @@ fn main() {
              // This is mostly to make it easy to debug the RE
              .help("Display diff of selected substrings"));
     if cfg!(debug_assertions) {
-        app = app.arg(Arg::with_name("debug")
-                      .required(false)
-                      .long("debug")
-                      .takes_value(false)
-                      // This is really only useful for small testcases.
-                      .help("Produce extensive debugging output"));
+        app.arg(Arg::with_name("debug")
+                .required(false)
+                .long("debug")
+                .takes_value(false)
+                // This is really only useful for small testcases.
+                .help("Produce extensive debugging output"))
+    } else {
+        app
     }
-    let matches = app.get_matches();
+}
 
+// The settings and the selection the command line asks for.
+fn configure(matches : &ArgMatches) -> (Conf, Selection) {
     let context = parse_usize(matches.value_of("context").unwrap());
+    let stream_window = if matches.is_present("stream") {
+        let window = parse_usize(matches.value_of("stream_window").unwrap());
+        if window == 0 {
+            eprintln!("The stream window needs to be at least one line");
+            exit(2)
+        }
+        Some (window)
+    } else {
+        None
+    };
+    let color = match conf::ColorWhen::new(matches.value_of("color").unwrap()) {
+        conf::ColorWhen::Auto => atty::is(atty::Stream::Stdout),
+        conf::ColorWhen::Always => true,
+        conf::ColorWhen::Never => false,
+    };
+    let output_format = if matches.is_present("side_by_side") {
+        conf::OutputFormat::SideBySide
+    } else {
+        conf::OutputFormat::new(matches.value_of("format").unwrap())
+    };
+    let width = parse_usize(matches.value_of("width").unwrap());
+    let section_re = if matches.is_present("show_c_function") {
+        Some (r"^[[:alpha:]$_]")
+    } else {
+        matches.value_of("section_re")
+    };
+    let section_re = section_re.map(|s| {
+        match RegexBuilder::new(s).multi_line(true).build() {
+            Ok (re) => re,
+            Err (err) => {
+                eprintln!("Could not compile regular expression `{}`: {}", s, err);
+                exit(2)
+            },
+        }
+    });
+    let rename_re = matches.value_of("rename_re").map(|s| {
+        match RegexBuilder::new(s).multi_line(true).build() {
+            Ok (re) => re,
+            Err (err) => {
+                eprintln!("Could not compile regular expression `{}`: {}", s, err);
+                exit(2)
+            },
+        }
+    });
+    // Each scoped ignore goes with the closest -r to its left.
+    let mut scoped_ignores = vec![];
+    if let (Some (ignores), Some (indices)) = (matches.values_of("scoped_ignore"),
+                                               matches.indices_of("scoped_ignore")) {
+        let re_indices : Vec<usize> = matches.indices_of("common_re")
+            .map_or(vec![], |i| i.collect());
+        for (ignore, idx) in ignores.zip(indices) {
+            match re_indices.iter().filter(|&&i| i < idx).count() {
+                0 => {
+                    eprintln!("Scoped ignore `{}` does not follow a -r", ignore);
+                    exit(2)
+                },
+                n => scoped_ignores.push((n - 1, ignore)),
+            }
+        }
+    }
+    let profile = matches.value_of("profile").map(|name| {
+        match profile::load(name) {
+            Ok (profile) => profile,
+            Err (err) => {
+                eprintln!("{}", err);
+                exit(2)
+            },
+        }
+    });
+    let res : Vec<&str> = matches.values_of("common_re").map_or(vec![], |v| v.collect());
+    let mut ignore_re = matches.value_of("ignore_re").map(|s| s.to_string());
+    if let Some (ref profile) = profile {
+        ignore_re = match (ignore_re, profile.ignore.clone()) {
+            (Some (ours), Some (theirs)) => Some (format!("(?:{})|(?:{})", ours, theirs)),
+            (ours, theirs) => ours.or(theirs),
+        }
+    }
+    let context = match output_format {
+        // The normal format never displays any context lines.
+        conf::OutputFormat::Normal => 0,
+        // Like diff -y, display the files in whole, unless asked for a
+        // number of context lines (or streaming).
+        conf::OutputFormat::SideBySide
+            if matches.occurrences_of("context") == 0 && stream_window.is_none() => {
+                usize::MAX
+            },
+        _ => context,
+    };
+    // The user's substitutions are applied first, so that they don't
+    // need to take the placeholders of --auto-ignore into account.
+    let mut substitutions : Vec<Substitution> = matches.values_of("substitute")
+        .map_or(vec![], |v| v.map(parse_substitution).collect());
+    if matches.is_present("auto_ignore") {
+        substitutions.extend(Substitution::auto_ignore_rules().into_iter().map(parse_substitution))
+    }
     let conf = Conf {
         context,
         mark_changed_context : matches.is_present("mark_changed_context"),
         display_selected : matches.is_present("display_selected"),
+        stream_window,
+        color,
+        output_format,
+        width,
+        section_re,
+        pair_changes : matches.is_present("pair_changes"),
+        rename_re,
+        multi_match : conf::MultiMatch::new(matches.value_of("multi_match").unwrap()),
+        regex_engine : conf::RegexEngine::new(matches.value_of("regex_engine").unwrap()),
+        numeric_tolerance : matches.value_of("numeric_tolerance").map(parse_tolerance),
+        cc_classes : CcClasses::new(matches.values_of("cc_class").map_or(vec![], |v| {
+            v.map(parse_cc_class).collect()
+        })),
+        substitutions,
         debug : matches.is_present("debug"),
-        ..Conf::default()
+        ..profile.as_ref().map_or(Conf::default(), |p| p.apply(Conf::default()))
     };
+    // Unless given explicitly, the context settings are those of the
+    // profile (if any).
     let conf = match matches.value_of("context_format") {
-        None => conf,
-        Some (v) => Conf { context_format : conf::ContextLineFormat::new(v), ..conf},
+        Some (v) if matches.occurrences_of("context_format") > 0 || profile.is_none() => {
+            Conf { context_format : conf::ContextLineFormat::new(v), ..conf}
+        },
+        _ => conf,
     };
     let conf = match matches.value_of("context_tokenization") {
+        Some (v) if matches.occurrences_of("context_tokenization") > 0 || profile.is_none() => {
+            Conf {
+                context_tokenization : parse_tokenization(v), ..conf
+            }
+        },
+        _ => conf,
+    };
+    let conf = match matches.value_of("diff_algorithm") {
         None => conf,
         Some (v) => Conf {
-            context_tokenization : conf::ContextLineTokenization::new(v), ..conf
+            diff_algorithm : conf::DiffAlgorithm::new(v), ..conf
+        },
+    };
+    let sel = Selection::build(&conf, if res.is_empty() { None } else { Some (res) },
+                               ignore_re.as_ref().map(|s| &s[..]), &scoped_ignores);
+    // The profile's REs only apply to the lines that none of those given
+    // on the command line matched.
+    let sel = match profile {
+        Some (ref profile) if !profile.regexes.is_empty() => {
+            sel.with_fallback(&conf, profile.regexes.iter().map(|s| &s[..]))
         },
+        _ => sel,
     };
-    let ecode = match diff_files(&mut io::stdout(),
-                                 &conf,
-                                 matches.values_of("common_re"),
-                                 matches.value_of("ignore_re"),
-                                 Path::new(matches.value_of("old").unwrap()),
-                                 Path::new(matches.value_of("new").unwrap())) {
+    (conf, sel)
+}
+
+fn main() {
+    let matches = app().get_matches();
+
+    if matches.is_present("print_auto_ignore") {
+        for rule in Substitution::auto_ignore_rules() {
+            println!("{}", rule)
+        }
+        exit(0)
+    }
+
+    if let Some (jobs) = matches.value_of("jobs") {
+        let jobs = parse_usize(jobs);
+        if jobs == 0 {
+            eprintln!("The number of jobs needs to be at least one");
+            exit(2)
+        }
+        if let Err (err) = rayon::ThreadPoolBuilder::new().num_threads(jobs).build_global() {
+            eprintln!("Could not start {} threads: {}", jobs, err);
+            exit(2)
+        }
+    }
+    let (conf, sel) = configure(&matches);
+    let ecode = match diff_selection(&mut io::stdout(),
+                                     &conf,
+                                     &sel,
+                                     Path::new(matches.value_of("old").unwrap()),
+                                     Path::new(matches.value_of("new").unwrap())) {
         Ok (ecode) => ecode,
         Err (err) => {
             eprintln!("Error comparing files: {}", err);
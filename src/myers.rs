@@ -0,0 +1,205 @@
+// Myers' O(ND) difference algorithm, using the linear space refinement
+// described in the same paper: find the middle snake of the optimal
+// edit path, then recurse on either side of it. The bisection follows
+// the one in Neil Fraser's diff-match-patch.
+use std::cmp;
+use super::lcs_diff::{DiffResult, DiffElement};
+
+// One step of an edit script, in terms of offsets into the old and
+// new sequences.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edit {
+    Common (usize, usize),
+    Remove (usize),
+    Add (usize),
+}
+
+/// Compute a minimal diff of the two sequences.
+pub fn diff<T : PartialEq + Clone>(old : &[T], new : &[T]) -> Vec<DiffResult<T>> {
+    let mut edits = vec![];
+    diff_range(old, new, 0, 0, &mut edits);
+    edits_to_results(old, new, &edits)
+}
+
+/// Append the edits for turning `old` into `new` to `edits`. The two
+/// sequences are assumed to start at `old_off` and `new_off` in the
+/// sequences the caller is actually diffing.
+pub fn diff_range<T : PartialEq>(old : &[T], new : &[T],
+                                 old_off : usize, new_off : usize,
+                                 edits : &mut Vec<Edit>) {
+    let prefix = old.iter().zip(new).take_while(|&(o, n)| o == n).count();
+    for i in 0..prefix {
+        edits.push(Edit::Common (old_off + i, new_off + i))
+    }
+    let (old, new) = (&old[prefix..], &new[prefix..]);
+    let (old_off, new_off) = (old_off + prefix, new_off + prefix);
+    let suffix = old.iter().rev().zip(new.iter().rev())
+        .take_while(|&(o, n)| o == n).count();
+    let (old, new) = (&old[..old.len() - suffix], &new[..new.len() - suffix]);
+
+    if old.is_empty() || new.is_empty() {
+        push_replacement(old.len(), new.len(), old_off, new_off, edits)
+    } else {
+        match middle_snake(old, new) {
+            Some ((x, y)) => {
+                diff_range(&old[..x], &new[..y], old_off, new_off, edits);
+                diff_range(&old[x..], &new[y..], old_off + x, new_off + y, edits);
+            },
+            None => push_replacement(old.len(), new.len(), old_off, new_off, edits),
+        }
+    }
+
+    for i in 0..suffix {
+        edits.push(Edit::Common (old_off + old.len() + i, new_off + new.len() + i))
+    }
+}
+
+// Nothing in common; remove all of old, add all of new.
+fn push_replacement(old_len : usize, new_len : usize,
+                    old_off : usize, new_off : usize,
+                    edits : &mut Vec<Edit>) {
+    for i in 0..old_len {
+        edits.push(Edit::Remove (old_off + i))
+    }
+    for j in 0..new_len {
+        edits.push(Edit::Add (new_off + j))
+    }
+}
+
+// Find a point on the middle snake of an optimal edit path, by running
+// the search both forward from the start and backward from the end,
+// until the two meet. Returns None if the sequences have nothing in
+// common.
+fn middle_snake<T : PartialEq>(old : &[T], new : &[T]) -> Option<(usize, usize)> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max_d = (n + m + 1) / 2;
+    let v_offset = max_d;
+    // Leave room for the k = d + 1 diagonal, which we look up on
+    // the first iteration.
+    let v_length = 2 * max_d + 2;
+    let mut v1 = vec![-1isize; v_length as usize];
+    let mut v2 = vec![-1isize; v_length as usize];
+    v1[(v_offset + 1) as usize] = 0;
+    v2[(v_offset + 1) as usize] = 0;
+    let delta = n - m;
+    // If the total number of characters is odd, then the front path
+    // will collide with the reverse path.
+    let front = delta % 2 != 0;
+    // Offsets for the start and end of the k loops, which prevent
+    // mapping space beyond the grid.
+    let mut k1start = 0;
+    let mut k1end = 0;
+    let mut k2start = 0;
+    let mut k2end = 0;
+    for d in 0..max_d {
+        // Walk the front path one step.
+        let mut k1 = -d + k1start;
+        while k1 <= d - k1end {
+            let k1_offset = (v_offset + k1) as usize;
+            let mut x1 = if k1 == -d || (k1 != d && v1[k1_offset - 1] < v1[k1_offset + 1]) {
+                v1[k1_offset + 1]
+            } else {
+                v1[k1_offset - 1] + 1
+            };
+            let mut y1 = x1 - k1;
+            while x1 < n && y1 < m && old[x1 as usize] == new[y1 as usize] {
+                x1 += 1;
+                y1 += 1;
+            }
+            v1[k1_offset] = x1;
+            if x1 > n {
+                // Ran off the right of the graph.
+                k1end += 2;
+            } else if y1 > m {
+                // Ran off the bottom of the graph.
+                k1start += 2;
+            } else if front {
+                let k2_offset = v_offset + delta - k1;
+                if k2_offset >= 0 && k2_offset < v_length && v2[k2_offset as usize] != -1 {
+                    // Mirror x2 onto the top-left coordinate system.
+                    let x2 = n - v2[k2_offset as usize];
+                    if x1 >= x2 {
+                        return Some ((x1 as usize, y1 as usize))
+                    }
+                }
+            }
+            k1 += 2;
+        }
+
+        // Walk the reverse path one step.
+        let mut k2 = -d + k2start;
+        while k2 <= d - k2end {
+            let k2_offset = (v_offset + k2) as usize;
+            let mut x2 = if k2 == -d || (k2 != d && v2[k2_offset - 1] < v2[k2_offset + 1]) {
+                v2[k2_offset + 1]
+            } else {
+                v2[k2_offset - 1] + 1
+            };
+            let mut y2 = x2 - k2;
+            while x2 < n && y2 < m
+                && old[(n - x2 - 1) as usize] == new[(m - y2 - 1) as usize] {
+                x2 += 1;
+                y2 += 1;
+            }
+            v2[k2_offset] = x2;
+            if x2 > n {
+                // Ran off the left of the graph.
+                k2end += 2;
+            } else if y2 > m {
+                // Ran off the top of the graph.
+                k2start += 2;
+            } else if !front {
+                let k1_offset = v_offset + delta - k2;
+                if k1_offset >= 0 && k1_offset < v_length && v1[k1_offset as usize] != -1 {
+                    let x1 = v1[k1_offset as usize];
+                    let y1 = v_offset + x1 - k1_offset;
+                    // Mirror x2 onto the top-left coordinate system.
+                    let x2 = n - x2;
+                    if x1 >= x2 {
+                        return Some ((x1 as usize, y1 as usize))
+                    }
+                }
+            }
+            k2 += 2;
+        }
+    }
+    None
+}
+
+/// Turn an edit script into the diff results the hunk state machine
+/// expects. Within each run of changes, removals are placed before
+/// additions, like diff does.
+pub fn edits_to_results<T : PartialEq + Clone>(old : &[T], new : &[T],
+                                              edits : &[Edit]) -> Vec<DiffResult<T>> {
+    let mut results = Vec::with_capacity(cmp::max(old.len(), new.len()));
+    let mut adds = vec![];
+    for e in edits {
+        match *e {
+            Edit::Common (o, n) => {
+                results.append(&mut adds);
+                results.push(DiffResult::Common (DiffElement {
+                    old_index : Some (o),
+                    new_index : Some (n),
+                    data : new[n].clone(),
+                }))
+            },
+            Edit::Remove (o) => {
+                results.push(DiffResult::Removed (DiffElement {
+                    old_index : Some (o),
+                    new_index : None,
+                    data : old[o].clone(),
+                }))
+            },
+            Edit::Add (n) => {
+                adds.push(DiffResult::Added (DiffElement {
+                    old_index : None,
+                    new_index : Some (n),
+                    data : new[n].clone(),
+                }))
+            },
+        }
+    }
+    results.append(&mut adds);
+    results
+}
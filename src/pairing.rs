@@ -0,0 +1,119 @@
+// Pairing up of removed and added lines, for --pair-changes. Within each
+// run of changes, we look for the pairs of removed and added lines whose
+// keys are most similar, keeping the pairs in the same order in both
+// files. The two lines of each pair are then displayed next to each
+// other, with the changed parts highlighted when coloring.
+use super::lcs_diff;
+use super::lcs_diff::DiffResult;
+use conf::{Conf, ContextLineTokenization};
+use wdiff::{Writeable, tokenize, tokenize_re};
+
+// Lines are only paired when their keys are at least this similar.
+const MIN_SIMILARITY : f64 = 0.5;
+
+// Pairing is quadratic in the length of the run of changes; runs with
+// more candidate pairs than this are left alone.
+const MAX_CANDIDATES : usize = 10000;
+
+fn common_len<T : PartialEq + Clone + Writeable>(old : &[T], new : &[T]) -> usize {
+    let mut buf = vec![];
+    for d in lcs_diff::diff(old, new) {
+        if let DiffResult::Common (el) = d {
+            el.data.write_to(&mut buf).unwrap()
+        }
+    }
+    buf.len()
+}
+
+// The fraction of the two keys that's in common, ignoring the EOL.
+fn similarity(conf : &Conf, old : &[u8], new : &[u8]) -> f64 {
+    let strip = |k : &[u8]| -> usize {
+        match k.last() {
+            Some (b'\n') => k.len() - 1,
+            _ => k.len(),
+        }
+    };
+    let (old, new) = (&old[..strip(old)], &new[..strip(new)]);
+    if old.is_empty() || new.is_empty() {
+        return 0.0
+    }
+    let common = match conf.context_tokenization {
+        ContextLineTokenization::Char => common_len(old, new),
+        ContextLineTokenization::Word (ref wb) => {
+            common_len(&tokenize(wb, old), &tokenize(wb, new))
+        },
+        ContextLineTokenization::Re (ref re) => {
+            common_len(&tokenize_re(re, old), &tokenize_re(re, new))
+        },
+    };
+    (2 * common) as f64 / (old.len() + new.len()) as f64
+}
+
+// Pair up the lines of a run of changes so that the total similarity of
+// the pairs is the highest possible.
+fn pair_run(conf : &Conf, removed : &[(usize, &[u8])],
+            added : &[(usize, &[u8])]) -> Vec<(usize, usize)> {
+    if removed.is_empty() || added.is_empty()
+        || removed.len() * added.len() > MAX_CANDIDATES {
+        return vec![]
+    }
+    let sim : Vec<Vec<f64>> = removed.iter().map(|&(_, o)| {
+        added.iter().map(|&(_, n)| similarity(conf, o, n)).collect()
+    }).collect();
+    // best[i][j] is the best we can do pairing removed[i..] with added[j..].
+    let mut best = vec![vec![0.0f64; added.len() + 1]; removed.len() + 1];
+    for i in (0..removed.len()).rev() {
+        for j in (0..added.len()).rev() {
+            let mut b = best[i + 1][j].max(best[i][j + 1]);
+            if sim[i][j] >= MIN_SIMILARITY {
+                b = b.max(sim[i][j] + best[i + 1][j + 1])
+            }
+            best[i][j] = b
+        }
+    }
+    let mut pairs = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < removed.len() && j < added.len() {
+        if sim[i][j] >= MIN_SIMILARITY && best[i][j] == sim[i][j] + best[i + 1][j + 1] {
+            pairs.push((removed[i].0, added[j].0));
+            i += 1;
+            j += 1
+        } else if best[i][j] == best[i + 1][j] {
+            i += 1
+        } else {
+            j += 1
+        }
+    }
+    pairs
+}
+
+/// Find the removed and added lines of the hunk items that should be
+/// displayed together. Returns the (old, new) offsets of the pairs, in
+/// the order they appear in.
+pub fn pair_changes<T, K>(conf : &Conf, items : &[DiffResult<T>],
+                          old_keys : &[K], new_keys : &[K]) -> Vec<(usize, usize)>
+where T : PartialEq + Clone, K : AsRef<[u8]>
+{
+    let mut pairs = vec![];
+    let mut removed = vec![];
+    let mut added = vec![];
+    for d in items {
+        match d {
+            DiffResult::Common (_) => {
+                pairs.extend(pair_run(conf, &removed, &added));
+                removed.clear();
+                added.clear()
+            },
+            DiffResult::Removed (el) => {
+                let o = el.old_index.unwrap();
+                removed.push((o, old_keys[o].as_ref()))
+            },
+            DiffResult::Added (el) => {
+                let n = el.new_index.unwrap();
+                added.push((n, new_keys[n].as_ref()))
+            },
+        }
+    }
+    pairs.extend(pair_run(conf, &removed, &added));
+    pairs
+}
@@ -0,0 +1,102 @@
+// Patience diff: anchor the diff on the elements that appear exactly
+// once in both sequences, keeping the longest run of such elements that
+// appears in the same order in both, then recurse between the anchors.
+// Regions without any unique common elements are diffed with Myers.
+use std::collections::HashMap;
+use std::hash::Hash;
+use super::lcs_diff::DiffResult;
+use myers;
+use myers::Edit;
+
+pub fn diff<T : Eq + Hash + Clone>(old : &[T], new : &[T]) -> Vec<DiffResult<T>> {
+    let mut edits = vec![];
+    diff_range(old, new, 0, 0, &mut edits);
+    myers::edits_to_results(old, new, &edits)
+}
+
+fn diff_range<T : Eq + Hash>(old : &[T], new : &[T],
+                             old_off : usize, new_off : usize,
+                             edits : &mut Vec<Edit>) {
+    let prefix = old.iter().zip(new).take_while(|&(o, n)| o == n).count();
+    for i in 0..prefix {
+        edits.push(Edit::Common (old_off + i, new_off + i))
+    }
+    let (old, new) = (&old[prefix..], &new[prefix..]);
+    let (old_off, new_off) = (old_off + prefix, new_off + prefix);
+    let suffix = old.iter().rev().zip(new.iter().rev())
+        .take_while(|&(o, n)| o == n).count();
+    let (old, new) = (&old[..old.len() - suffix], &new[..new.len() - suffix]);
+
+    let anchors = longest_increasing(&unique_common(old, new));
+    if anchors.is_empty() {
+        myers::diff_range(old, new, old_off, new_off, edits)
+    } else {
+        let mut prev_o = 0;
+        let mut prev_n = 0;
+        for (o, n) in anchors {
+            diff_range(&old[prev_o..o], &new[prev_n..n],
+                       old_off + prev_o, new_off + prev_n, edits);
+            edits.push(Edit::Common (old_off + o, new_off + n));
+            prev_o = o + 1;
+            prev_n = n + 1;
+        }
+        diff_range(&old[prev_o..], &new[prev_n..],
+                   old_off + prev_o, new_off + prev_n, edits);
+    }
+
+    for i in 0..suffix {
+        edits.push(Edit::Common (old_off + old.len() + i, new_off + new.len() + i))
+    }
+}
+
+// The offsets of the elements that appear exactly once in each
+// sequence, ordered by their offset in the old sequence.
+fn unique_common<T : Eq + Hash>(old : &[T], new : &[T]) -> Vec<(usize, usize)> {
+    // Number of occurrences and last offset, in old and new.
+    let mut seen : HashMap<&T, (usize, usize, usize, usize)> = HashMap::new();
+    for (i, el) in old.iter().enumerate() {
+        let e = seen.entry(el).or_insert((0, 0, 0, 0));
+        e.0 += 1;
+        e.1 = i;
+    }
+    for (j, el) in new.iter().enumerate() {
+        if let Some (e) = seen.get_mut(el) {
+            e.2 += 1;
+            e.3 = j;
+        }
+    }
+    let mut pairs : Vec<(usize, usize)> = seen.values()
+        .filter(|&&(nold, _, nnew, _)| nold == 1 && nnew == 1)
+        .map(|&(_, o, _, n)| (o, n))
+        .collect();
+    pairs.sort();
+    pairs
+}
+
+// The longest subsequence of pairs (already sorted on their first element)
+// that is also increasing in the second element, found by patience sorting.
+fn longest_increasing(pairs : &[(usize, usize)]) -> Vec<(usize, usize)> {
+    // Index of the pair at the top of each pile.
+    let mut tops : Vec<usize> = vec![];
+    // For each pair, the top of the previous pile when it was placed.
+    let mut prev : Vec<Option<usize>> = Vec::with_capacity(pairs.len());
+    for (i, &(_, n)) in pairs.iter().enumerate() {
+        let pile = match tops.binary_search_by_key(&n, |&t| pairs[t].1) {
+            Ok (p) | Err (p) => p,
+        };
+        prev.push(if pile > 0 { Some (tops[pile - 1]) } else { None });
+        if pile == tops.len() {
+            tops.push(i)
+        } else {
+            tops[pile] = i
+        }
+    }
+    let mut ret = vec![];
+    let mut cur = tops.last().cloned();
+    while let Some (i) = cur {
+        ret.push(pairs[i]);
+        cur = prev[i];
+    }
+    ret.reverse();
+    ret
+}
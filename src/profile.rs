@@ -0,0 +1,188 @@
+// Named sets of options, for --profile. Each top-level table of a
+// profiles file is a profile:
+//
+//   [myapp]
+//   regex = ['^\S+ \S+ (.*)$']
+//   ignore = '0x[[:xdigit:]]+'
+//   context-format = "cc"
+//   context-tokenization = "word"
+//
+// Profiles are looked up in the .subdiff.toml of the current directory
+// (or the closest of its parents that has one), then in the profiles.toml
+// of the user's configuration directory, then among the built-in ones.
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use toml;
+use conf::{Conf, ContextLineFormat, ContextLineTokenization};
+
+const BUILTIN_PROFILES : &str = r#"
+# strace, optionally with -f, -t/-tt and -T. Addresses, times and
+# successful return values are ignored.
+[strace]
+regex = ['^(?:\[pid +\d+\] )?(?:[\d:.]+ +)?(.*?) *(?:<\d+\.\d+>)?$']
+ignore = '0x[[:xdigit:]]+|tv_[un]?sec=\d+|= \d+$'
+
+# journalctl in the short formats. Only the identifier and the message
+# are compared, not the time, host or PID.
+[journalctl]
+regex = [
+    '^(?:\w{3} [ \d]\d \d\d:\d\d:\d\d(?:\.\d+)?|\d{4}-\d\d-\d\dT\S+) \S+ ([^\[:]+)(?:\[\d+\])?(:.*)$',
+    '^-- (Boot) [[:xdigit:]]+ --$',
+]
+context-format = "cc"
+
+# Traditional (and RFC 5424 timestamped) syslog files.
+[syslog]
+regex = ['^(?:\w{3} [ \d]\d \d\d:\d\d:\d\d|\d{4}-\d\d-\d\dT\S+) \S+ ([^\[:]+)(?:\[\d+\])?(:.*)$']
+context-format = "cc"
+
+# objdump -d. Instructions are compared without their addresses and
+# encodings, symbols without their addresses.
+[objdump]
+regex = [
+    '^\s+[[:xdigit:]]+:\t.*\t(.*)$',
+    '^[[:xdigit:]]+ (<.*>:)$',
+    '^\S+:\s+(file format .*)$',
+]
+ignore = '0x[[:xdigit:]]+|\s*#.*|\b[[:xdigit:]]+ <'
+context-format = "cc"
+"#;
+
+#[derive(Clone)]
+pub struct Profile {
+    // For -r, in order.
+    pub regexes : Vec<String>,
+    // For -i.
+    pub ignore : Option<String>,
+    pub context_format : Option<ContextLineFormat>,
+    pub context_tokenization : Option<ContextLineTokenization>,
+}
+
+fn string(name : &str, key : &str, value : &toml::Value) -> Result<String, String> {
+    match value.as_str() {
+        Some (s) => Ok (s.to_string()),
+        None => Err (format!("Profile `{}`: `{}` needs to be a string", name, key)),
+    }
+}
+
+impl Profile {
+    pub fn builtin_names() -> Vec<&'static str> {
+        vec!["strace", "journalctl", "syslog", "objdump"]
+    }
+
+    pub fn builtin(name : &str) -> Option<Profile> {
+        Profile::find(name, BUILTIN_PROFILES).unwrap()
+    }
+
+    fn from_toml(name : &str, table : &toml::Value) -> Result<Profile, String> {
+        let table = match table.as_table() {
+            Some (table) => table,
+            None => return Err (format!("Profile `{}` needs to be a table", name)),
+        };
+        let mut profile = Profile {
+            regexes : vec![],
+            ignore : None,
+            context_format : None,
+            context_tokenization : None,
+        };
+        for (key, value) in table {
+            if key == "regex" {
+                // A single RE doesn't need to be in a list.
+                profile.regexes = match value.as_array() {
+                    Some (res) => res.iter().map(|re| string(name, key, re))
+                        .collect::<Result<_, _>>()?,
+                    None => vec![string(name, key, value)?],
+                }
+            } else if key == "ignore" {
+                profile.ignore = Some (string(name, key, value)?)
+            } else if key == "context-format" {
+                let v = string(name, key, value)?;
+                match v.parse::<ContextLineFormat>() {
+                    Ok (format) => profile.context_format = Some (format),
+                    Err (_) => {
+                        return Err (format!("Profile `{}`: unsupported context-format `{}`", name, v))
+                    },
+                }
+            } else if key == "context-tokenization" {
+                let v = string(name, key, value)?;
+                match ContextLineTokenization::new(&v) {
+                    Ok (tokenization) => profile.context_tokenization = Some (tokenization),
+                    Err (err) => {
+                        return Err (format!("Profile `{}`: unsupported context-tokenization `{}` ({})",
+                                            name, v, err))
+                    },
+                }
+            } else {
+                return Err (format!("Profile `{}`: unknown setting `{}`", name, key))
+            }
+        }
+        Ok (profile)
+    }
+
+    /// Look up the profile in the contents of a profiles file.
+    pub fn find(name : &str, text : &str) -> Result<Option<Profile>, String> {
+        let profiles = match text.parse::<toml::Value>() {
+            Ok (profiles) => profiles,
+            Err (err) => return Err (err.to_string()),
+        };
+        match profiles.get(name) {
+            Some (table) => Profile::from_toml(name, table).map(Some),
+            None => Ok (None),
+        }
+    }
+
+    /// The settings of the profile, on top of conf.
+    pub fn apply(&self, conf : Conf) -> Conf {
+        Conf {
+            context_format : self.context_format.unwrap_or(conf.context_format),
+            context_tokenization : self.context_tokenization.clone()
+                .unwrap_or(conf.context_tokenization),
+            ..conf
+        }
+    }
+}
+
+// The profiles files, in the order they're searched in.
+fn profile_files() -> Vec<PathBuf> {
+    let mut ret = vec![];
+    if let Ok (cwd) = env::current_dir() {
+        if let Some (dir) = cwd.ancestors().find(|d| d.join(".subdiff.toml").is_file()) {
+            ret.push(dir.join(".subdiff.toml"))
+        }
+    }
+    let config = match env::var_os("XDG_CONFIG_HOME") {
+        Some (dir) => Some (PathBuf::from(dir)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+    };
+    if let Some (config) = config {
+        ret.push(config.join("subdiff").join("profiles.toml"))
+    }
+    ret
+}
+
+/// Find the profile in the profiles files or, failing that, among the
+/// built-in ones.
+pub fn load(name : &str) -> Result<Profile, String> {
+    for path in profile_files() {
+        let mut text = String::new();
+        match File::open(&path) {
+            Ok (mut f) => {
+                if let Err (err) = f.read_to_string(&mut text) {
+                    return Err (format!("Could not read `{}`: {}", path.display(), err))
+                }
+            },
+            Err (_) => continue,
+        }
+        match Profile::find(name, &text) {
+            Ok (Some (profile)) => return Ok (profile),
+            Ok (None) => (),
+            Err (err) => return Err (format!("{}: {}", path.display(), err)),
+        }
+    }
+    Profile::builtin(name).ok_or_else(|| {
+        format!("Unknown profile `{}` (the built-in ones are: {})",
+                name, Profile::builtin_names().join(", "))
+    })
+}
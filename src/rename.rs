@@ -0,0 +1,97 @@
+// Comparison up to a consistent renaming, for --rename. The values
+// matched by the RE (addresses, file descriptors, PIDs, ...) are masked
+// in the keys before diffing. Then, going over the lines that were
+// found to be in common, we require that each value in the old file
+// always corresponds to the same value in the new one, and vice versa.
+// Common lines that break the mapping are reported as changed (see
+// check_values).
+use std::borrow::Cow;
+use std::collections::HashMap;
+use regex::bytes::Regex;
+
+/// The key, with each of the values matched by `re` replaced by a
+/// placeholder, so that where the values are is still part of the key.
+/// The placeholder is a NUL followed by another NUL; a NUL in the rest
+/// of the key is followed by a 1 instead, so that the two can't be
+/// confused.
+pub fn mask<'a>(re : &Regex, key : &'a [u8]) -> Cow<'a, [u8]> {
+    if !re.is_match(key) && !key.contains(&0) {
+        return Cow::Borrowed (key)
+    }
+    let mut masked = Vec::with_capacity(key.len());
+    let escape = |masked : &mut Vec<u8>, s : &[u8]| {
+        for &b in s {
+            masked.push(b);
+            if b == 0 {
+                masked.push(1)
+            }
+        }
+    };
+    let mut last = 0;
+    for m in re.find_iter(key) {
+        escape(&mut masked, &key[last..m.start()]);
+        masked.extend_from_slice(b"\0\0");
+        last = m.end()
+    }
+    escape(&mut masked, &key[last..]);
+    Cow::Owned (masked)
+}
+
+/// The mapping between the values in the two files, as established by
+/// the lines we've gone over so far.
+pub struct Renaming {
+    old_to_new : HashMap<Vec<u8>, Vec<u8>>,
+    new_to_old : HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Default for Renaming {
+    fn default() -> Renaming {
+        Renaming::new()
+    }
+}
+
+impl Renaming {
+    pub fn new() -> Renaming {
+        Renaming {
+            old_to_new : HashMap::new(),
+            new_to_old : HashMap::new(),
+        }
+    }
+
+    fn consistent(&self, old : &[u8], new : &[u8]) -> bool {
+        let agrees = |map : &HashMap<Vec<u8>, Vec<u8>>, k : &[u8], v : &[u8]| {
+            match map.get(k) {
+                None => true,
+                Some (mapped) => &mapped[..] == v,
+            }
+        };
+        agrees(&self.old_to_new, old, new) && agrees(&self.new_to_old, new, old)
+    }
+
+    /// Extend the mapping with the values of a pair of lines whose masked
+    /// keys are the same. Returns false, leaving the mapping untouched,
+    /// if the values of the lines contradict it (or each other).
+    pub fn extend(&mut self, re : &Regex, old_key : &[u8], new_key : &[u8]) -> bool {
+        let old_vals : Vec<&[u8]> = re.find_iter(old_key).map(|m| m.as_bytes()).collect();
+        let new_vals : Vec<&[u8]> = re.find_iter(new_key).map(|m| m.as_bytes()).collect();
+        if old_vals.len() != new_vals.len() {
+            return false
+        }
+        let mut added = vec![];
+        for (o, n) in old_vals.into_iter().zip(new_vals) {
+            if !self.consistent(o, n) {
+                for o in added {
+                    let n = self.old_to_new.remove(o).unwrap();
+                    self.new_to_old.remove(&n);
+                }
+                return false
+            }
+            if !self.old_to_new.contains_key(o) {
+                self.old_to_new.insert(o.to_vec(), n.to_vec());
+                self.new_to_old.insert(n.to_vec(), o.to_vec());
+                added.push(o)
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,262 @@
+// Side by side output, like that of diff -y. The files (or, given a
+// number of context lines, each hunk) are displayed as two columns, the
+// old lines on the left and the new ones on the right, separated by a
+// gutter that marks the kind of change:
+//
+//   ` ` lines in common
+//   `|` changed lines (a removed line paired with an added one)
+//   `<` removed lines
+//   `>` added lines
+//
+// Context lines that differ in their ignored parts show both versions,
+// with the changed fragments marked.
+use std::io;
+use std::io::prelude::*;
+use super::lcs_diff;
+use super::lcs_diff::{DiffResult, DiffElement};
+use conf::{Conf, ContextLineTokenization};
+use wdiff::{Writeable, tokenize, tokenize_re};
+use color;
+
+const TAB_WIDTH : usize = 8;
+
+// The text of one of the two columns of a row, with the bytes that
+// are part of an intra-line change flagged.
+struct Cell {
+    text : Vec<u8>,
+    changed : Vec<bool>,
+}
+
+impl Cell {
+    fn new() -> Cell {
+        Cell {
+            text : vec![],
+            changed : vec![],
+        }
+    }
+
+    fn plain(line : &[u8]) -> Cell {
+        let mut cell = Cell::new();
+        cell.push(line, false);
+        cell
+    }
+
+    fn push(&mut self, s : &[u8], changed : bool) {
+        // The EOL is implied by the row.
+        let s = match s.last() {
+            Some (b'\n') => &s[..(s.len() - 1)],
+            _ => s,
+        };
+        self.text.extend_from_slice(s);
+        self.changed.extend(s.iter().map(|_| changed))
+    }
+}
+
+// Build the two cells for a context line, marking the tokens that differ.
+fn context_cells<T>(old : &[T], new : &[T]) -> (Cell, Cell)
+where T : PartialEq + Clone + Writeable
+{
+    let mut cells = (Cell::new(), Cell::new());
+    for d in lcs_diff::diff(old, new) {
+        let mut buf = vec![];
+        match d {
+            DiffResult::Common (el) => {
+                el.data.write_to(&mut buf).unwrap();
+                cells.0.push(&buf, false);
+                cells.1.push(&buf, false);
+            },
+            DiffResult::Removed (el) => {
+                el.data.write_to(&mut buf).unwrap();
+                cells.0.push(&buf, true);
+            },
+            DiffResult::Added (el) => {
+                el.data.write_to(&mut buf).unwrap();
+                cells.1.push(&buf, true);
+            },
+        }
+    }
+    cells
+}
+
+// The two cells for a pair of lines, tokenized according to
+// --context-tokenization.
+fn line_cells(conf : &Conf, line_o : &[u8], line_n : &[u8]) -> (Cell, Cell) {
+    match conf.context_tokenization {
+        ContextLineTokenization::Char => context_cells(line_o, line_n),
+        ContextLineTokenization::Word (ref wb) => {
+            context_cells(&tokenize(wb, line_o), &tokenize(wb, line_n))
+        },
+        ContextLineTokenization::Re (ref re) => {
+            context_cells(&tokenize_re(re, line_o), &tokenize_re(re, line_n))
+        },
+    }
+}
+
+/// Flag the bytes of two lines that are part of an intra-line change.
+/// The EOLs aren't flagged.
+pub fn changed_bytes(conf : &Conf, line_o : &[u8], line_n : &[u8]) -> (Vec<bool>, Vec<bool>) {
+    let (old, new) = line_cells(conf, line_o, line_n);
+    (old.changed, new.changed)
+}
+
+// Continuation bytes of UTF-8 sequences don't take up a column.
+fn is_continuation(b : u8) -> bool {
+    b & 0xc0 == 0x80
+}
+
+// Render a cell into at most `width` columns. Returns the number of
+// columns actually used, so that the caller can pad the output. Without
+// color, a span that's open always leaves room for its closing `}`.
+fn render(out : &mut Vec<u8>, conf : &Conf, cell : &Cell, width : usize,
+          line_style : Option<&[u8]>, marker : &str, span_style : &[u8]) -> usize {
+    let mut col = 0;
+    let mut in_span = false;
+    let restore = |out : &mut Vec<u8>| {
+        if let Some (style) = line_style {
+            if conf.color {
+                out.extend_from_slice(style)
+            }
+        }
+    };
+    restore(out);
+    for (&b, &changed) in cell.text.iter().zip(&cell.changed) {
+        if changed != in_span {
+            if conf.color {
+                if changed {
+                    out.extend_from_slice(span_style)
+                } else {
+                    out.extend_from_slice(color::RESET);
+                    restore(out)
+                }
+            } else {
+                let m = if changed { marker } else { "}" };
+                let reserved = if changed { 1 } else { 0 };
+                if col + m.len() + reserved > width {
+                    break
+                }
+                out.extend(m.bytes());
+                col += m.len();
+            }
+            in_span = changed
+        }
+        let limit = if in_span && !conf.color { width - 1 } else { width };
+        if b == b'\t' {
+            let next = (col / TAB_WIDTH + 1) * TAB_WIDTH;
+            let next = if next > limit { limit } else { next };
+            while col < next {
+                out.push(b' ');
+                col += 1
+            }
+        } else if is_continuation(b) {
+            out.push(b)
+        } else if col < limit {
+            out.push(b);
+            col += 1
+        } else {
+            break
+        }
+    }
+    if in_span && !conf.color {
+        out.push(b'}');
+        col += 1
+    }
+    if conf.color && (in_span || line_style.is_some()) {
+        out.extend_from_slice(color::RESET)
+    }
+    col
+}
+
+/// The narrowest output that fits the gutter and a column on each side.
+pub const MIN_WIDTH : usize = 5;
+
+fn write_row(out : &mut Write, conf : &Conf, gutter : u8,
+             left : Option<&Cell>, right : Option<&Cell>,
+             left_style : Option<&[u8]>, right_style : Option<&[u8]>) -> io::Result<()> {
+    let half = conf.width.saturating_sub(3) / 2;
+    let mut row = vec![];
+    let used = match left {
+        None => 0,
+        Some (cell) => render(&mut row, conf, cell, half, left_style,
+                              "{-", color::REMOVED_SPAN),
+    };
+    match right {
+        None => {
+            if gutter != b' ' {
+                row.extend((used..half).map(|_| b' '));
+                row.push(b' ');
+                row.push(gutter);
+            }
+        },
+        Some (cell) => {
+            row.extend((used..half).map(|_| b' '));
+            row.push(b' ');
+            row.push(gutter);
+            row.push(b' ');
+            render(&mut row, conf, cell, half, right_style, "{+", color::ADDED_SPAN);
+        },
+    }
+    row.push(b'\n');
+    out.write_all(&row)
+}
+
+fn write_common_row(out : &mut Write, conf : &Conf,
+                    line_o : &[u8], line_n : &[u8]) -> io::Result<()> {
+    if line_o == line_n {
+        let cell = Cell::plain(line_o);
+        return write_row(out, conf, b' ', Some (&cell), Some (&cell), None, None)
+    }
+    let (old, new) = line_cells(conf, line_o, line_n);
+    let gutter = if conf.mark_changed_context {
+        b'!'
+    } else {
+        b' '
+    };
+    write_row(out, conf, gutter, Some (&old), Some (&new), None, None)
+}
+
+fn write_changes<L : AsRef<[u8]>>(out : &mut Write, conf : &Conf,
+                                  old_lines : &[L], new_lines : &[L],
+                                  removed : &mut Vec<usize>,
+                                  added : &mut Vec<usize>) -> io::Result<()> {
+    let nrows = if removed.len() > added.len() {
+        removed.len()
+    } else {
+        added.len()
+    };
+    for i in 0..nrows {
+        let left = removed.get(i).map(|&o| Cell::plain(old_lines[o].as_ref()));
+        let right = added.get(i).map(|&n| Cell::plain(new_lines[n].as_ref()));
+        let gutter = match (&left, &right) {
+            (Some (_), Some (_)) => b'|',
+            (Some (_), None) => b'<',
+            _ => b'>',
+        };
+        write_row(out, conf, gutter, left.as_ref(), right.as_ref(),
+                  Some (color::REMOVED), Some (color::ADDED))?;
+    }
+    removed.clear();
+    added.clear();
+    Ok (())
+}
+
+/// Write out the items of a hunk side by side.
+pub fn write_items<L>(out : &mut Write, conf : &Conf, items : &[DiffResult<L>],
+                      old_lines : &[L], new_lines : &[L]) -> io::Result<()>
+where L : AsRef<[u8]> + PartialEq + Clone
+{
+    // Runs of removed and added lines are displayed next to each other.
+    let mut removed = vec![];
+    let mut added = vec![];
+    for d in items {
+        match d {
+            DiffResult::Common (DiffElement { old_index : Some (o), new_index : Some (n), ..}) => {
+                write_changes(out, conf, old_lines, new_lines, &mut removed, &mut added)?;
+                write_common_row(out, conf, old_lines[*o].as_ref(), new_lines[*n].as_ref())?;
+            },
+            DiffResult::Removed (DiffElement { old_index : Some (o), ..}) => removed.push(*o),
+            DiffResult::Added (DiffElement { new_index : Some (n), ..}) => added.push(*n),
+            _ => panic!("Can't print DiffElement with neither side"),
+        }
+    }
+    write_changes(out, conf, old_lines, new_lines, &mut removed, &mut added)
+}
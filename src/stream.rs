@@ -0,0 +1,288 @@
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use rayon;
+use super::lcs_diff::{DiffResult, DiffElement};
+use super::{Selection, open_input, file_headers, diff_keys, check_values};
+use conf::{Conf, OutputFormat};
+use html;
+use json;
+use pairing;
+use rename::Renaming;
+use hunked::{Hunk, HunkBuilder, DisplayableHunk, Side, Sections};
+
+// One of the two inputs, as seen through the sliding window.
+struct Window {
+    input : Box<BufRead + Send>,
+    eof : bool,
+    // File offset of lines[0] and keys[0].
+    base : usize,
+    // The lines that might still have to be displayed, up to the
+    // last line we've read.
+    lines : Vec<Vec<u8>>,
+    // The selected parts of those lines.
+    keys : Vec<Vec<u8>>,
+    // File offset of the first line we have yet to diff.
+    next : usize,
+}
+
+impl Window {
+    fn new(input : Box<BufRead + Send>) -> Window {
+        Window {
+            input,
+            eof : false,
+            base : 0,
+            lines : vec![],
+            keys : vec![],
+            next : 0,
+        }
+    }
+
+    // The keys of the lines we have yet to diff.
+    fn pending(&self) -> &[Vec<u8>] {
+        &self.keys[(self.next - self.base)..]
+    }
+
+    fn fill(&mut self, conf : &Conf, sel : &Selection, path : &Path,
+            size : usize) -> io::Result<()> {
+        let mut batch = vec![];
+        while !self.eof && self.pending().len() + batch.len() < size {
+            let mut line = vec![];
+            if self.input.read_until(b'\n', &mut line)? == 0 {
+                self.eof = true;
+                break
+            }
+            batch.push(line)
+        }
+        let off = self.base + self.lines.len();
+        let keys : Vec<Vec<u8>> = sel.pick(conf, path, off, &batch).into_iter()
+            .map(|k| k.into_owned()).collect();
+        if conf.display_selected {
+            self.lines.extend(keys.iter().cloned())
+        } else {
+            self.lines.extend(batch)
+        }
+        self.keys.extend(keys);
+        Ok (())
+    }
+
+    // Lines close to the end of the window might align differently
+    // once we've seen the lines that follow them. Unless there are no
+    // more lines to come, only the first half of the window is
+    // considered settled.
+    fn settled(&self) -> usize {
+        let len = self.pending().len();
+        if self.eof {
+            len
+        } else {
+            len - len / 2
+        }
+    }
+
+    fn consume(&mut self, n : usize) {
+        self.next += n
+    }
+
+    fn trim(&mut self, off : usize) {
+        if off > self.base {
+            self.lines.drain(..(off - self.base));
+            self.keys.drain(..(off - self.base));
+            self.base = off
+        }
+    }
+}
+
+// When the windows have no lines in common, we keep doubling their size,
+// in the hope of getting the two inputs back in sync, up to this many
+// times the requested window size.
+const MAX_WINDOW_GROWTH : usize = 16;
+
+// How many of the diff results we can commit to. We stop at the last
+// common line in the settled part of both windows. Failing that, any
+// common line will do; all that matters is that we make progress.
+fn commit_len<T : PartialEq + Clone>(diff : &[DiffResult<T>],
+                                     old_settled : usize,
+                                     new_settled : usize) -> Option<usize> {
+    let mut last_common = None;
+    let mut last_settled = None;
+    for (i, d) in diff.iter().enumerate() {
+        if let DiffResult::Common (el) = d {
+            last_common = Some (i);
+            match (el.old_index, el.new_index) {
+                (Some (o), Some (n)) if o < old_settled && n < new_settled => {
+                    last_settled = Some (i)
+                },
+                _ => (),
+            }
+        }
+    }
+    last_settled.or(last_common).map(|i| i + 1)
+}
+
+// Convert the offsets of a diff result from window offsets to file offsets.
+fn shift<T : PartialEq + Clone>(d : DiffResult<T>,
+                                old_next : usize,
+                                new_next : usize) -> DiffResult<T> {
+    let shift_el = |el : DiffElement<T>| {
+        DiffElement {
+            old_index : el.old_index.map(|o| o + old_next),
+            new_index : el.new_index.map(|n| n + new_next),
+            data : el.data,
+        }
+    };
+    match d {
+        DiffResult::Common (el) => DiffResult::Common (shift_el(el)),
+        DiffResult::Added (el) => DiffResult::Added (shift_el(el)),
+        DiffResult::Removed (el) => DiffResult::Removed (shift_el(el)),
+    }
+}
+
+// The two files we're comparing, as far as we've read them.
+struct Inputs<'a> {
+    old_path : &'a Path,
+    new_path : &'a Path,
+    old : &'a Window,
+    new : &'a Window,
+}
+
+fn dump(out : &mut Write, conf : &Conf, sel : &Selection, have_diffs : &mut bool,
+        inputs : &Inputs, sections : &mut Sections,
+        hunk : Option<&Hunk<Vec<u8>>>) -> io::Result<()> {
+    let hunk = match hunk {
+        None => return Ok (()),
+        Some (hunk) => hunk,
+    };
+    let Inputs { old_path, new_path, old, new } = *inputs;
+    let old_side = Side { path : old_path, lines : &old.lines, keys : &old.keys,
+                          base : old.base };
+    let new_side = Side { path : new_path, lines : &new.lines, keys : &new.keys,
+                          base : new.base };
+    if conf.output_format == OutputFormat::Json {
+        *have_diffs = true;
+        return json::write_hunk(out, conf, hunk, &old_side, &new_side)
+    }
+    // We only know there are differences once we've found the first hunk.
+    if !*have_diffs {
+        if conf.output_format == OutputFormat::Html {
+            html::file_begin(out, old_path, new_path)?;
+        } else {
+            file_headers(out, conf, old_path, new_path)?;
+        }
+        *have_diffs = true
+    }
+    if conf.output_format == OutputFormat::Html {
+        return html::write_hunk(out, conf, sel, hunk, &old_side, &new_side)
+    }
+    let mut hunk = hunk.rebase(old.base, new.base);
+    sections.scan(conf, &old.lines, old.base, hunk.old_start);
+    hunk.section = sections.last();
+    if conf.pair_changes && conf.output_format == OutputFormat::Unified {
+        hunk.pairs = pairing::pair_changes(conf, &hunk.items, &old.keys, &new.keys)
+    }
+    hunk.do_write(conf, &old.lines, &new.lines, out)
+}
+
+/// Compare two files by diffing a window of `window` lines at a time,
+/// so that neither file is ever read into memory as a whole. Whatever
+/// part of each window we've settled on is fed to the hunk state
+/// machine, which outputs the hunks as soon as they are complete.
+///
+/// The result is always a correct diff, but it may not be a minimal
+/// one when matching lines are farther apart than the window size.
+/// When the windows have no lines in common at all, they are allowed to
+/// grow up to `MAX_WINDOW_GROWTH` times their original size. Hunks that
+/// grow past that size are split, so that memory use is bounded by the
+/// window size, whatever the size of the changes.
+pub fn diff_streaming(out : &mut Write, conf : &Conf, sel : &Selection,
+                      old_path : &Path, new_path : &Path,
+                      window : usize) -> io::Result<i32> {
+    let mut old = Window::new(open_input(old_path)?);
+    let mut new = Window::new(open_input(new_path)?);
+    let mut builder = HunkBuilder::new();
+    let mut renaming = Renaming::new();
+    let mut sections = Sections::new();
+    let mut have_diffs = false;
+    let mut size = window;
+    loop {
+        let (old_res, new_res) = rayon::join(|| old.fill(conf, sel, old_path, size),
+                                             || new.fill(conf, sel, new_path, size));
+        old_res?;
+        new_res?;
+        if old.pending().is_empty() && new.pending().is_empty() {
+            break
+        }
+        let diff = diff_keys(conf, old.pending(), new.pending());
+        let ncommit = if old.eof && new.eof {
+            diff.len()
+        } else {
+            match commit_len(&diff, old.settled(), new.settled()) {
+                Some (ncommit) => ncommit,
+                None if size < window * MAX_WINDOW_GROWTH => {
+                    size *= 2;
+                    continue
+                },
+                // We've given up on finding a common line, so the
+                // whole of both windows goes into the diff.
+                None => diff.len(),
+            }
+        };
+        size = window;
+        dprintln!(conf.debug, "Committing {} of {} diff results at offsets ({}, {})",
+                  ncommit, diff.len(), old.next, new.next);
+        // Only the lines we commit to get to extend the renaming.
+        let diff : Vec<DiffResult<Vec<u8>>> = diff.into_iter().take(ncommit).collect();
+        let diff = check_values(conf, &mut renaming, diff, old.pending(), new.pending());
+        let mut nold = 0;
+        let mut nnew = 0;
+        {
+            let inputs = Inputs {
+                old_path,
+                new_path,
+                old : &old,
+                new : &new,
+            };
+            let mut dump_hunk = |hunk : Option<&Hunk<Vec<u8>>>| {
+                dump(out, conf, sel, &mut have_diffs, &inputs, &mut sections, hunk)
+            };
+            for d in diff {
+                match d {
+                    DiffResult::Common (_) => {
+                        nold += 1;
+                        nnew += 1;
+                    },
+                    DiffResult::Removed (_) => nold += 1,
+                    DiffResult::Added (_) => nnew += 1,
+                }
+                let d = shift(d, old.next, new.next);
+                builder.push(conf, &mut dump_hunk, d)?;
+            }
+            // A long enough change would otherwise keep all of its
+            // lines in the windows.
+            builder.split(conf, &mut dump_hunk, window * MAX_WINDOW_GROWTH)?;
+        }
+        old.consume(nold);
+        new.consume(nnew);
+        let (old_off, new_off) = builder.pending_offsets();
+        // The old lines we let go of might still hold the section
+        // heading of the hunks to come.
+        sections.scan(conf, &old.lines, old.base, old_off);
+        old.trim(old_off);
+        new.trim(new_off);
+    }
+    {
+        let inputs = Inputs {
+            old_path,
+            new_path,
+            old : &old,
+            new : &new,
+        };
+        let mut dump_hunk = |hunk : Option<&Hunk<Vec<u8>>>| {
+            dump(out, conf, sel, &mut have_diffs, &inputs, &mut sections, hunk)
+        };
+        builder.finish(conf, &mut dump_hunk)?;
+    }
+    if have_diffs && conf.output_format == OutputFormat::Html {
+        html::file_end(out)?;
+    }
+    Ok (if have_diffs { 1 } else { 0 })
+}
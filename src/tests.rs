@@ -3,6 +3,10 @@ use itertools::Itertools;
 use std::process::Command;
 use std::ffi::OsStr;
 use std::usize;
+use std::fs;
+use std::thread;
+use std::fmt::Debug;
+use std::os::unix::ffi::OsStrExt;
 use conf::ContextLineFormat::*;
 use conf::CharacterClassExpansion::*;
 use conf::ContextLineTokenization::*;
@@ -13,11 +17,20 @@ enum TestDiff {
 }
 
 fn diff_two_files(conf : &Conf, old : &Path, new : &Path) -> Vec<u8> {
+    let context = conf.context.to_string();
+    let mut args = match conf.output_format {
+        OutputFormat::Context => vec![OsStr::new("-C"), OsStr::new(&context)],
+        OutputFormat::Normal => vec![],
+        _ => vec![OsStr::new("-U"), OsStr::new(&context)],
+    };
+    if let Some (ref re) = conf.section_re {
+        args.push(OsStr::new("-F"));
+        args.push(OsStr::new(re.as_str()));
+    }
+    args.push(old.as_os_str());
+    args.push(new.as_os_str());
     let outp = Command::new("diff")
-        .args(&[OsStr::new("-U"),
-                OsStr::new(&conf.context.to_string()),
-                old.as_os_str(),
-                new.as_os_str()])
+        .args(&args)
         .output().unwrap();
     outp.stdout
 }
@@ -28,36 +41,49 @@ fn test_diff<'a, I>(conf : &Conf, dir : &temporary::Directory, test: TestDiff,
 where
     I : IntoIterator<Item = &'a str> + Clone
 {
+    let sel = Selection::build(conf, res, ignore_re, &[]);
+    test_diff_selection(conf, dir, test, &sel, lines1, lines2)
+}
+
+// The lines can be arbitrary bytes.
+fn test_diff_selection<L : AsRef<[u8]>>(conf : &Conf, dir : &temporary::Directory,
+                                        test: TestDiff, sel : &Selection,
+                                        lines1 : &[L], lines2 : &[L]) {
     let old_p = dir.join("old");
     let mut old = File::create(&old_p).unwrap();
     let new_p = dir.join("new");
     let mut new = File::create(&new_p).unwrap();
 
     for l in lines1 {
-        write!(&mut old, "{}", l).unwrap();
+        old.write_all(l.as_ref()).unwrap();
     }
     old.flush().unwrap();
     for l in lines2 {
-        write!(&mut new, "{}", l).unwrap();
+        new.write_all(l.as_ref()).unwrap();
     }
     new.flush().unwrap();
     let mut our_output : Vec<u8> = vec![];
-    diff_files(&mut our_output, conf, res, ignore_re, &old_p, &new_p).unwrap();
+    diff_selection(&mut our_output, conf, sel, &old_p, &new_p).unwrap();
     let expected = match test {
         TestDiff::AgainstDiff => diff_two_files(conf, &old_p, &new_p),
+        // No differences, so no headers either.
+        TestDiff::AgainstGiven (ref s) if s.is_empty() => vec![],
         TestDiff::AgainstGiven (s) => {
             let mut complete = vec![];
-            file_header(&mut complete, b"---", &old_p).unwrap();
-            file_header(&mut complete, b"+++", &new_p).unwrap();
+            file_headers(&mut complete, conf, &old_p, &new_p).unwrap();
             complete.extend(s);
             complete
         },
     };
-    if our_output != expected {
+    check_output(&our_output, &expected)
+}
+
+fn check_output(ours : &[u8], expected : &[u8]) {
+    if ours != expected {
         eprintln!("outputs differ! ours:");
-        io::stderr().write(&our_output).unwrap();
+        io::stderr().write_all(ours).unwrap();
         eprintln!("expected:");
-        io::stderr().write(&expected).unwrap();
+        io::stderr().write_all(expected).unwrap();
         panic!("Output differs to the expected bytes")
     }
 }
@@ -97,7 +123,7 @@ fn long_test_of_combos_against_diff() {
         for chunk in prod.chunks(2) {
             let lines = &lines;
             for context in 0..2 {
-                let conf = Conf {context, ..conf};
+                let conf = Conf {context, ..conf.clone()};
                 s.spawn(move |_| {
                     do_chunk(&conf, cnt, chunk, lines)
                 });
@@ -186,13 +212,129 @@ fn do_newline_at_eof(conf : &Conf) {
 #[test]
 fn newline_at_eof_handling() {
     for context in 0..2 {
+        for &output_format in &[OutputFormat::Unified, OutputFormat::Context] {
+            let conf = Conf {
+                debug : false,
+                context,
+                output_format,
+                ..Conf::default()
+            };
+            do_newline_at_eof(&conf)
+        }
+    }
+    let conf = Conf {
+        context : 0,
+        output_format : OutputFormat::Normal,
+        ..Conf::default()
+    };
+    do_newline_at_eof(&conf)
+}
+
+#[test]
+fn context_and_normal_formats() {
+    let tmpdir = temporary::Directory::new("context-normal").unwrap();
+    let no_res : Option<Vec<&'static str>> = None;
+    let old = ["a\n", "b\n", "c\n", "d\n", "e\n", "f\n", "g\n", "h\n", "i\n", "j\n", "k\n"];
+    let new = ["a\n", "B\n", "c\n", "d\n", "e\n", "f\n", "g\n", "h\n", "X\n", "i\n", "j\n"];
+    for context in 0..4 {
         let conf = Conf {
-            debug : false,
             context,
+            output_format : OutputFormat::Context,
             ..Conf::default()
         };
-        do_newline_at_eof(&conf)
+        test_diff(&conf, &tmpdir, TestDiff::AgainstDiff, no_res.clone(), None, &old, &new);
     }
+    let conf = Conf {
+        context : 0,
+        output_format : OutputFormat::Normal,
+        ..Conf::default()
+    };
+    test_diff(&conf, &tmpdir, TestDiff::AgainstDiff, no_res.clone(), None, &old, &new);
+
+    // Context lines are displayed according to the context format in
+    // both halves of the hunk.
+    let conf = Conf {
+        context : 1,
+        output_format : OutputFormat::Context,
+        ..Conf::default()
+    };
+    test_diff(&conf, &tmpdir, TestDiff::AgainstGiven (join_lines(vec![
+        "***************",
+        "*** 1,2 ****",
+        "  {-1}{+3} a",
+        "! 2 b",
+        "--- 1,2 ----",
+        "  {-1}{+3} a",
+        "! 4 c",
+    ])), Some (vec![r"^\d+ (.*)$"]), None, &["1 a\n", "2 b\n"], &["3 a\n", "4 c\n"]);
+    tmpdir.remove().unwrap()
+}
+
+#[test]
+fn section_headings_match_diff() {
+    let tmpdir = temporary::Directory::new("section-headings").unwrap();
+    let no_res : Option<Vec<&'static str>> = None;
+    let old = ["int f(void)  \n", "a\n", "b\n", "c\n", "d\n", "e\n",
+               "  int g(int x)\n", "f\n", "g\n", "h\n", "i\n"];
+    let new = ["int f(void)  \n", "a\n", "B\n", "c\n", "d\n", "e\n",
+               "  int g(int x)\n", "f\n", "g\n", "H\n", "i\n"];
+    // The headings are looked for incrementally, including when streaming.
+    let formats = [OutputFormat::Unified, OutputFormat::Context];
+    for (&output_format, &stream_window) in iproduct!(&formats, &[None, Some (3)]) {
+        for re in &["^int", "int", "^[a-z]$"] {
+            let conf = Conf {
+                context : 1,
+                output_format,
+                section_re : Some (RegexBuilder::new(re).multi_line(true).build().unwrap()),
+                stream_window,
+                ..Conf::default()
+            };
+            test_diff(&conf, &tmpdir, TestDiff::AgainstDiff, no_res.clone(), None, &old, &new);
+        }
+    }
+    tmpdir.remove().unwrap()
+}
+
+#[test]
+fn changes_are_paired() {
+    let re = Some (vec![r"^\S+ (.*)$"]);
+    let old = ["10:00 start job alpha\n", "10:01 load config file main.conf\n",
+               "10:02 connect to db host1\n", "10:03 done\n"];
+    let new = ["11:00 start job alpha\n", "11:01 cleanup\n",
+               "11:02 load config file other.conf\n", "11:03 connect to db host2\n",
+               "11:04 done\n"];
+    let conf = Conf {
+        context : 1,
+        pair_changes : true,
+        ..Conf::default()
+    };
+    // The dissimilar line is left alone; the similar ones are displayed
+    // next to each other, in the order they appear in. The hunk header
+    // still counts every line.
+    test_given(&conf, re.clone(), None, &old, &new, join_lines(vec![
+        "@@ -1,4 +1,5 @@",
+        " {-10}{+11}:00 start job alpha",
+        "+11:01 cleanup",
+        "-10:01 load config file main.conf",
+        "+11:02 load config file other.conf",
+        "-10:02 connect to db host1",
+        "+11:03 connect to db host2",
+        " {-10}{+11}:{-03}{+04} done",
+    ]));
+    // With color, the changed parts of the pairs are highlighted.
+    let conf = Conf {
+        color : true,
+        context_tokenization : ContextLineTokenization::word(),
+        ..conf
+    };
+    let mut expected = vec![];
+    expected.extend(b"\x1b[36m@@ -1 +1 @@\x1b[0m\n".iter());
+    expected.extend(b"\x1b[31m-\x1b[7;31m10\x1b[0m\x1b[31m:02 connect to db \
+                      \x1b[7;31mhost1\x1b[0m\n".iter());
+    expected.extend(b"\x1b[32m+\x1b[7;32m11\x1b[0m\x1b[32m:02 connect to db \
+                      \x1b[7;32mhost2\x1b[0m\n".iter());
+    test_given(&conf, re, None, &["10:02 connect to db host1\n"],
+               &["11:02 connect to db host2\n"], expected);
 }
 
 fn join_lines(lines : Vec<&str>) -> Vec<u8> {
@@ -208,9 +350,15 @@ fn test_given<'a, I>(conf : &Conf, res : Option<I>, ignore_re : Option<&str>,
 where
     I : IntoIterator<Item = &'a str> + Clone
 {
+    let sel = Selection::build(conf, res, ignore_re, &[]);
+    test_given_selection(conf, &sel, old, new, expected)
+}
+
+fn test_given_selection<L : AsRef<[u8]>>(conf : &Conf, sel : &Selection,
+                                         old : &[L], new : &[L], expected : Vec<u8>) {
     let tmpdir = temporary::Directory::new("sel-smoke-test").unwrap();
-    test_diff(&conf, &tmpdir, TestDiff::AgainstGiven(expected),
-              res, ignore_re, old, new)
+    test_diff_selection(conf, &tmpdir, TestDiff::AgainstGiven(expected),
+                        sel, old, new)
 }
 
 #[test]
@@ -399,7 +547,7 @@ fn word_character_class_wide() {
         debug : false,
         context : 100,
         context_format : CC (Wide),
-        context_tokenization : Word,
+        context_tokenization : ContextLineTokenization::word(),
         ..Conf::default()
     };
     let re = Some (vec![
@@ -427,7 +575,7 @@ fn word_character_class_narrow() {
         debug : false,
         context : 100,
         context_format : CC (Narrow),
-        context_tokenization : Word,
+        context_tokenization : ContextLineTokenization::word(),
         ..Conf::default()
     };
     let re = Some (vec![
@@ -448,3 +596,1065 @@ fn word_character_class_narrow() {
                &["yd\n", "ab de\n", "ab 34 ef 2b lf ?f3\n"],
                expected)
 }
+
+#[test]
+fn user_character_classes() {
+    let classes = vec![
+        CcClass::new("x", r"0x[0-9a-f]+").unwrap(),
+        CcClass::builtin("uuid").unwrap(),
+        CcClass::builtin("timestamp").unwrap(),
+    ];
+    let re = Some (vec![
+        r"^([a-z]+)",
+    ]);
+    let old = ["xd\n",
+               "a 0x7ffd3a, 0x1 b\n",
+               "id 1b4e28ba-2fa1-11d2-883f-0016d3cca427\n",
+               "at 2024-01-02T10:00:00Z c\n"];
+    let new = ["yd\n",
+               "a 0x7ffe10, 0x1 b\n",
+               "id 1b4e28ba-2fa1-11d2-883f-0016d3cca4ff\n",
+               "at 2024-01-03T11:22:33Z c\n"];
+    for tokenization in [ContextLineTokenization::word(), Char] {
+        let conf = Conf {
+            context : 100,
+            context_format : CC (Narrow),
+            context_tokenization : tokenization,
+            cc_classes : CcClasses::new(classes.clone()),
+            ..Conf::default()
+        };
+        let expected = join_lines(vec![
+            r"@@ -1,4 +1,4 @@",
+            r"-xd",
+            r"+yd",
+            r" a \x+, 0x1 b",
+            r" id \uuid+",
+            r" at \timestamp+ c",
+        ]);
+        test_given(&conf, re.clone(), None, &old, &new, expected);
+        let conf = Conf {
+            context_format : CC (Wide),
+            ..conf
+        };
+        let expected = join_lines(vec![
+            r"@@ -1,4 +1,4 @@",
+            r"-xd",
+            r"+yd",
+            r" a \x{8}, 0x1 b",
+            r" id \uuid{36}",
+            r" at \timestamp{20} c",
+        ]);
+        test_given(&conf, re.clone(), None, &old, &new, expected);
+    }
+}
+
+// Run subdiff with the given arguments on two files, as the command line
+// would. Returns the exit code and the output, minus the file headers.
+fn run_cli(args : &[&str], old : &[&str], new : &[&str]) -> (i32, Vec<u8>) {
+    let tmpdir = temporary::Directory::new("cli-test").unwrap();
+    let old_path = tmpdir.join("old");
+    let new_path = tmpdir.join("new");
+    write_file(&old_path, old);
+    write_file(&new_path, new);
+    let mut argv = vec!["subdiff", "--color=never"];
+    argv.extend(args);
+    argv.push(old_path.to_str().unwrap());
+    argv.push(new_path.to_str().unwrap());
+    let matches = app().get_matches_from(argv);
+    let (conf, sel) = configure(&matches);
+    let mut output = vec![];
+    let ecode = diff_selection(&mut output, &conf, &sel, &old_path, &new_path).unwrap();
+    tmpdir.remove().unwrap();
+    let body = output.splitn(3, |&c| c == b'\n').nth(2).unwrap_or(&[]).to_vec();
+    (ecode, body)
+}
+
+#[test]
+fn cc_classes_from_the_command_line() {
+    let old = ["xd\n", "a 0x7ffd3a, 0x1 b\n", "id 1b4e28ba-2fa1-11d2-883f-0016d3cca427\n"];
+    let new = ["yd\n", "a 0x7ffe10, 0x1 b\n", "id 1b4e28ba-2fa1-11d2-883f-0016d3cca4ff\n"];
+    let (ecode, output) = run_cli(&["-c", "100", "-r", "^([a-z]+)",
+                                    "--cc-class", "x=0x[0-9a-f]+", "--cc-class", "uuid",
+                                    "--context-format=ccwide"],
+                                  &old, &new);
+    assert_eq!(ecode, 1);
+    assert_eq!(String::from_utf8_lossy(&output), String::from_utf8_lossy(&join_lines(vec![
+        r"@@ -1,3 +1,3 @@",
+        r"-xd",
+        r"+yd",
+        r" a \x{8}, 0x1 b",
+        r" id \uuid{36}",
+    ])));
+}
+
+#[test]
+fn custom_tokenization() {
+    let conf = Conf {
+        context : 100,
+        context_format : ContextLineFormat::Wdiff,
+        context_tokenization : ContextLineTokenization::new("re:[^,]+").unwrap(),
+        ..Conf::default()
+    };
+    let re = Some (vec![
+        r"^([a-z]) ",
+    ]);
+    let expected = join_lines(vec![
+        r"@@ -1,3 +1,3 @@",
+        r"-xd",
+        r"+yd",
+        r" p key=1,{-name=foo bar}{+name=foo baz},z=2",
+        r" q ,{-a}{+b},,c",
+    ]);
+    test_given(&conf, re, None,
+               &["xd\n", "p key=1,name=foo bar,z=2\n", "q ,a,,c\n"],
+               &["yd\n", "p key=1,name=foo baz,z=2\n", "q ,b,,c\n"],
+               expected)
+}
+
+#[test]
+fn substitutions() {
+    let substitutions = vec![
+        Substitution::parse(r"s/0x[0-9a-f]+/ADDR/g").unwrap(),
+        Substitution::parse(r"s|(\w+)=\d+|\1=N|g").unwrap(),
+    ];
+    let conf = Conf {
+        context : 100,
+        substitutions,
+        ..Conf::default()
+    };
+    let no_res : Option<Vec<&'static str>> = None;
+    // With -i, these would become foo(, ) and foo().
+    let expected = join_lines(vec![
+        r"@@ -1,3 +1,3 @@",
+        r"-call foo(0x1, 0x2) a",
+        r"+call foo(0x3) a",
+        r" call bar({-0x10}{+0x20}) b",
+        r" x={-1}{+5} y={-2}{+7}",
+    ]);
+    test_given(&conf, no_res.clone(), None,
+               &["call foo(0x1, 0x2) a\n", "call bar(0x10) b\n", "x=1 y=2\n"],
+               &["call foo(0x3) a\n", "call bar(0x20) b\n", "x=5 y=7\n"],
+               expected);
+    // Without the g flag, only the first match is replaced.
+    let conf = Conf {
+        context : 100,
+        substitutions : vec![Substitution::parse(r"s/\d/N/").unwrap()],
+        ..Conf::default()
+    };
+    let expected = join_lines(vec![
+        r"@@ -1,2 +1,2 @@",
+        r" {-1}{+2} 3",
+        r"-4 5",
+        r"+4 6",
+    ]);
+    test_given(&conf, no_res.clone(), None,
+               &["1 3\n", "4 5\n"],
+               &["2 3\n", "4 6\n"],
+               expected);
+    // There are no $ references in sed replacements.
+    for &(subst, key, replaced) in &[(r"s/x/$y/", "cost x", "cost $y"),
+                                     (r"s/(x)/\$1&/", "x", "$1x")] {
+        let subst = Substitution::parse(subst).unwrap();
+        assert_eq!(&subst.apply(key.as_bytes())[..], replaced.as_bytes())
+    }
+    for s in &["s/a/b", "y/a/b/", "s/a/b/x", "s/(/b/"] {
+        assert!(Substitution::parse(s).is_err())
+    }
+}
+
+#[test]
+fn consistent_renaming() {
+    let old = ["open fd=3 obj=0x55aa01\n", "read fd=3 obj=0x55aa01\n",
+               "read fd=4 obj=0x55aa02\n", "close obj=0x55aa01\n",
+               "free obj=0x55aa02\n", "done\n"];
+    let new = ["open fd=5 obj=0x7f0010\n", "read fd=5 obj=0x7f0010\n",
+               "read fd=6 obj=0x7f0020\n", "close obj=0x7f0020\n",
+               "free obj=0x7f0020\n", "done\n"];
+    // Only the close breaks the mapping established by the lines before it.
+    let expected = join_lines(vec![
+        r"@@ -1,6 +1,6 @@",
+        r" open fd={-3}{+5} obj={-0x55aa01}{+0x7f0010}",
+        r" read fd={-3}{+5} obj={-0x55aa01}{+0x7f0010}",
+        r" read fd={-4}{+6} obj={-0x55aa02}{+0x7f0020}",
+        r"-close obj=0x55aa01",
+        r"+close obj=0x7f0020",
+        r" free obj={-0x55aa02}{+0x7f0020}",
+        r" done",
+    ]);
+    let no_res : Option<Vec<&'static str>> = None;
+    let rename_re = Regex::new(r"0x[0-9a-f]+|\bfd=\d+").unwrap();
+    for &stream_window in &[None, Some (1), Some (2), Some (1000)] {
+        let conf = Conf {
+            context : 100,
+            rename_re : Some (rename_re.clone()),
+            stream_window,
+            ..Conf::default()
+        };
+        test_given(&conf, no_res.clone(), None, &old, &new, expected.clone());
+    }
+    // A value that moved to another place in the line is a change.
+    let conf = Conf {
+        rename_re : Some (Regex::new(r"\d+").unwrap()),
+        ..Conf::default()
+    };
+    test_given(&conf, no_res.clone(), None, &["x1 y\n"], &["x y1\n"], join_lines(vec![
+        "@@ -1 +1 @@",
+        "-x1 y",
+        "+x y1",
+    ]));
+}
+
+#[test]
+fn numeric_tolerance() {
+    let old = ["read 3 <0.000100> s\n", "write 1 <0.000200> s\n", "open 4 <0.001000> s\n"];
+    let new = ["read 3 <0.000104> s\n", "write 1 <0.000900> s\n", "open 5 <0.001010> s\n"];
+    let no_res : Option<Vec<&'static str>> = None;
+    // Only the numbers captured by the RE are compared within the
+    // tolerance, the rest of them need to be the same.
+    for tol in &[r"<([\d.]+)>:5%", r"<([\d.]+)>:abs:0.0001"] {
+        for &stream_window in &[None, Some (1)] {
+            let conf = Conf {
+                context : 100,
+                numeric_tolerance : Some (NumericTolerance::parse(tol).unwrap()),
+                stream_window,
+                ..Conf::default()
+            };
+            let expected = join_lines(vec![
+                r"@@ -1,3 +1,3 @@",
+                r" read 3 <0.{-000100}{+000104}> s",
+                r"-write 1 <0.000200> s",
+                r"-open 4 <0.001000> s",
+                r"+write 1 <0.000900> s",
+                r"+open 5 <0.001010> s",
+            ]);
+            test_given(&conf, no_res.clone(), None, &old, &new, expected);
+        }
+    }
+    for tol in &["5", "x:-1%", "abs:", "(:5%"] {
+        assert!(NumericTolerance::parse(tol).is_err())
+    }
+}
+
+#[test]
+fn multi_match_policies() {
+    // Both REs match the first two lines, only the first one matches
+    // the last line.
+    let re = Some (vec![
+        r"^(\w+) ",
+        r" (\w+ \w+)$",
+    ]);
+    let old = ["a b c\n", "d e f\n", "g h\n"];
+    let new = ["a x c\n", "x e f\n", "g x\n"];
+    let expected = |policy, lines : Vec<&str>| {
+        let conf = Conf {
+            context : 100,
+            multi_match : MultiMatch::new(policy),
+            ..Conf::default()
+        };
+        test_given(&conf, re.clone(), None, &old, &new, join_lines(lines))
+    };
+    expected("first", vec![
+        "@@ -1,3 +1,3 @@",
+        " a {-b}{+x} c",
+        "-d e f",
+        "+x e f",
+        " g {-h}{+x}",
+    ]);
+    expected("longest", vec![
+        "@@ -1,3 +1,3 @@",
+        "-a b c",
+        "+a x c",
+        " {-d}{+x} e f",
+        " g {-h}{+x}",
+    ]);
+    expected("all-concat", vec![
+        "@@ -1,3 +1,3 @@",
+        "-a b c",
+        "-d e f",
+        "+a x c",
+        "+x e f",
+        " g {-h}{+x}",
+    ]);
+    // The parts are used in the order they appear in the line, once
+    // even if they overlap. Each RE's scoped ignores only drop parts
+    // that no other RE keeps.
+    let conf = Conf {
+        multi_match : MultiMatch::AllConcat,
+        ..Conf::default()
+    };
+    let mre = MultiRe::build(RegexEngine::Regex, [r"(\w+ \w+)$", r"^(\w+ \w+)"],
+                             &[(0, "c"), (1, "b")]);
+    assert_eq!(mre.sel(&conf, b"a b c\n").unwrap().unwrap(), b"a b \n");
+    let mre = MultiRe::build(RegexEngine::Regex, [r"(\w+ \w+)$", r"^(\w+ \w+)"], &[]);
+    let sel = mre.ranges(&conf, b"a b c\n").unwrap().unwrap();
+    assert_eq!(sel.ranges, vec![0..5]);
+    assert!(sel.ignored.is_empty());
+}
+
+fn write_file(p : &Path, lines : &[&str]) {
+    let mut f = File::create(p).unwrap();
+    for l in lines {
+        write!(&mut f, "{}", l).unwrap();
+    }
+    f.flush().unwrap();
+}
+
+#[test]
+fn directories_are_compared_recursively() {
+    let conf = Conf {
+        debug : false,
+        context : 1,
+        ..Conf::default()
+    };
+    let tmpdir = temporary::Directory::new("dir-test").unwrap();
+    let old = tmpdir.join("old");
+    let new = tmpdir.join("new");
+    fs::create_dir_all(old.join("sub")).unwrap();
+    fs::create_dir_all(new.join("sub")).unwrap();
+    write_file(&old.join("changed"), &["1 a\n", "2 b\n"]);
+    write_file(&new.join("changed"), &["3 a\n", "4 c\n"]);
+    write_file(&old.join("sub/same"), &["1 a\n"]);
+    write_file(&new.join("sub/same"), &["2 a\n"]);
+    write_file(&old.join("sub/only_old"), &["a\n"]);
+    write_file(&new.join("only_new"), &["a\n"]);
+    let mut our_output : Vec<u8> = vec![];
+    let ecode = diff_files(&mut our_output, &conf, Some (vec![r"^\d+ (\w+)$"]), None,
+                           &old, &new).unwrap();
+    assert_eq!(ecode, 1);
+    let mut expected = vec![];
+    file_header(&mut expected, &Conf::default(), b"---", &old.join("changed")).unwrap();
+    file_header(&mut expected, &Conf::default(), b"+++", &new.join("changed")).unwrap();
+    expected.extend(join_lines(vec![
+        "@@ -1,2 +1,2 @@",
+        " {-1}{+3} a",
+        "-2 b",
+        "+4 c",
+    ]));
+    writeln!(&mut expected, "Only in {}: only_new", new.display()).unwrap();
+    writeln!(&mut expected, "Only in {}: only_old", old.join("sub").display()).unwrap();
+    check_output(&our_output, &expected);
+    tmpdir.remove().unwrap()
+}
+
+#[test]
+fn directory_loops_are_detected() {
+    let tmpdir = temporary::Directory::new("dir-loop-test").unwrap();
+    let old = tmpdir.join("old");
+    let new = tmpdir.join("new");
+    for dir in &[&old, &new] {
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        write_file(&dir.join("sub/same"), &["a\n"]);
+        ::std::os::unix::fs::symlink("..", dir.join("sub/loop")).unwrap();
+    }
+    let mut our_output : Vec<u8> = vec![];
+    let no_res : Option<Vec<&'static str>> = None;
+    let ecode = diff_files(&mut our_output, &Conf::default(), no_res, None,
+                           &old, &new).unwrap();
+    assert_eq!(ecode, 2);
+    assert_eq!(String::from_utf8_lossy(&our_output), "");
+    tmpdir.remove().unwrap()
+}
+
+#[test]
+fn stdin_is_identical_to_itself() {
+    // Whatever the test harness left on the standard input, it's not
+    // supposed to be read.
+    for &stream_window in &[None, Some (10)] {
+        let conf = Conf {
+            stream_window,
+            ..Conf::default()
+        };
+        let mut our_output : Vec<u8> = vec![];
+        let no_res : Option<Vec<&'static str>> = None;
+        let stdin = Path::new("-");
+        let ecode = diff_files(&mut our_output, &conf, no_res, None, stdin, stdin).unwrap();
+        assert_eq!(ecode, 0);
+        assert!(our_output.is_empty());
+    }
+}
+
+#[test]
+fn fifo_input_works() {
+    let conf = Conf {
+        debug : false,
+        context : 1,
+        ..Conf::default()
+    };
+    let tmpdir = temporary::Directory::new("fifo-test").unwrap();
+    let old = tmpdir.join("old");
+    let new = tmpdir.join("new");
+    let status = Command::new("mkfifo").arg(&old).status().unwrap();
+    assert!(status.success());
+    write_file(&new, &["a\n", "c\n"]);
+    let writer = {
+        let old = old.clone();
+        thread::spawn(move || write_file(&old, &["a\n", "b\n"]))
+    };
+    let mut our_output : Vec<u8> = vec![];
+    let no_res : Option<Vec<&'static str>> = None;
+    let ecode = diff_files(&mut our_output, &conf, no_res, None, &old, &new).unwrap();
+    writer.join().unwrap();
+    assert_eq!(ecode, 1);
+    // The header of the FIFO carries the current time, so only
+    // check the part that's not time-dependent.
+    let mut header = b"--- ".to_vec();
+    header.extend(old.as_os_str().as_bytes());
+    header.push(b'\t');
+    assert!(our_output.starts_with(&header));
+    let expected = join_lines(vec![
+        "@@ -1,2 +1,2 @@",
+        " a",
+        "-b",
+        "+c",
+    ]);
+    assert!(our_output.ends_with(&expected));
+    tmpdir.remove().unwrap()
+}
+
+// The lines of the old and new file.
+type Case<'a> = (&'a [&'a [u8]], &'a [&'a [u8]]);
+
+#[test]
+fn mapped_input_matches_diff() {
+    let tmpdir = temporary::Directory::new("mmap-test").unwrap();
+    let path = tmpdir.join("file");
+    fs::write(&path, b"a\nb").unwrap();
+    match read_input(&path).unwrap() {
+        Input::Mapped (ref m) => assert_eq!(split_lines(m), vec![&b"a\n"[..], &b"b"[..]]),
+        Input::Buffered (_) => panic!("Regular file was not mapped"),
+    }
+    fs::write(&path, b"").unwrap();
+    assert!(split_lines(&read_input(&path).unwrap()).is_empty());
+
+    let no_res : Option<Vec<&'static str>> = None;
+    let sel = Selection::build(&Conf::default(), no_res, None, &[]);
+    let cases : [Case; 6] = [
+        // With and without the EOL at the end of the file.
+        (&[b"a\n", b"b\n", b"c\n"], &[b"a\n", b"x\n", b"c\n"]),
+        (&[b"a\n", b"b\n", b"c"], &[b"a\n", b"x\n", b"c"]),
+        (&[b"a\n", b"b\n", b"c\n"], &[b"a\n", b"b\n", b"c"]),
+        // Empty files.
+        (&[], &[b"a\n"]),
+        (&[b"a"], &[]),
+        // Not UTF-8.
+        (&[b"\xff\xfe\n", b"b\n"], &[b"\xff\xfd\n", b"b\n", b"\xc3"]),
+    ];
+    for &(old, new) in cases.iter() {
+        for &context in &[0, 3] {
+            let conf = Conf {
+                context,
+                ..Conf::default()
+            };
+            test_diff_selection(&conf, &tmpdir, TestDiff::AgainstDiff, &sel, old, new);
+        }
+    }
+    tmpdir.remove().unwrap()
+}
+
+#[test]
+fn streaming_matches_diff() {
+    let tmpdir = temporary::Directory::new("stream-test").unwrap();
+    let old : Vec<String> = (0..100).map(|i| format!("{}\n", i)).collect();
+    let mut new = old.clone();
+    new[3] = "x\n".to_string();
+    new.remove(20);
+    new.insert(21, "y\n".to_string());
+    new.insert(22, "z\n".to_string());
+    new.drain(40..50);
+    let last = new.len() - 1;
+    new[last] = "w".to_string();
+    let old : Vec<&str> = old.iter().map(|s| &s[..]).collect();
+    let new : Vec<&str> = new.iter().map(|s| &s[..]).collect();
+    let no_res : Option<Vec<&'static str>> = None;
+    for context in 0..4 {
+        for window in &[1, 4, 8, 1000] {
+            let conf = Conf {
+                context,
+                stream_window : Some (*window),
+                ..Conf::default()
+            };
+            test_diff(&conf, &tmpdir, TestDiff::AgainstDiff, no_res.clone(), None,
+                      &old, &new);
+        }
+    }
+    tmpdir.remove().unwrap()
+}
+
+#[test]
+fn long_changes_are_split_when_streaming() {
+    let old : Vec<String> = (0..200).map(|i| format!("{}\n", i)).collect();
+    let mut new = old.clone();
+    for (i, l) in new.iter_mut().enumerate().take(150).skip(50) {
+        *l = format!("n{}\n", i);
+    }
+    let old : Vec<&str> = old.iter().map(|s| &s[..]).collect();
+    let new : Vec<&str> = new.iter().map(|s| &s[..]).collect();
+    let (ecode, out) = run_cli(&["--stream", "--stream-window=2", "-c", "0"], &old, &new);
+    assert_eq!(ecode, 1);
+    let out = String::from_utf8(out).unwrap();
+    // Every hunk is consistent with its header, and, put together, they
+    // still cover the whole change.
+    let (mut hunks, mut removed, mut added) = (0, vec![], vec![]);
+    let (mut expected_removes, mut expected_adds) = (0, 0);
+    for l in out.lines() {
+        if l.starts_with("@@") {
+            assert_eq!((expected_removes, expected_adds), (0, 0));
+            let counts : Vec<usize> = l.split(' ').skip(1).take(2)
+                .map(|r| r.split(',').nth(1).map_or(1, |c| c.parse().unwrap()))
+                .collect();
+            expected_removes = counts[0];
+            expected_adds = counts[1];
+            // The cap, plus whatever the last two (fully grown) windows
+            // brought in.
+            assert!(expected_removes + expected_adds <= 2 * 16 + 2 * 2 * 16);
+            hunks += 1;
+        } else if let Some (l) = l.strip_prefix('-') {
+            removed.push(format!("{}\n", l));
+            expected_removes -= 1;
+        } else if let Some (l) = l.strip_prefix('+') {
+            added.push(format!("{}\n", l));
+            expected_adds -= 1;
+        }
+    }
+    assert_eq!((expected_removes, expected_adds), (0, 0));
+    assert!(hunks > 1, "{}", out);
+    assert_eq!(removed, &old[50..150]);
+    assert_eq!(added, &new[50..150]);
+}
+
+// A tiny deterministic PRNG, so that the tests are reproducible.
+fn lcg(state : &mut u64) -> u64 {
+    *state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    *state >> 33
+}
+
+#[test]
+fn output_is_the_same_for_any_number_of_jobs() {
+    let tmpdir = temporary::Directory::new("jobs-test").unwrap();
+    let old = tmpdir.join("old");
+    let new = tmpdir.join("new");
+    // Enough lines for the selection to be split between the threads.
+    let mut state = 7;
+    let lines : Vec<String> = (0..3000).map(|i| {
+        let call = ["read", "write"][i % 2];
+        format!("{} {} {}\n", lcg(&mut state) % 1000, call, lcg(&mut state) % 8)
+    }).collect();
+    let mut changed = lines.clone();
+    for _ in 0..100 {
+        let i = (lcg(&mut state) as usize) % changed.len();
+        changed[i] = format!("{} open {}\n", lcg(&mut state) % 1000, lcg(&mut state) % 8)
+    }
+    let lines : Vec<&str> = lines.iter().map(|s| &s[..]).collect();
+    let changed : Vec<&str> = changed.iter().map(|s| &s[..]).collect();
+    write_file(&old, &lines);
+    write_file(&new, &changed);
+    for &stream_window in &[None, Some (50)] {
+        let conf = Conf {
+            stream_window,
+            ..Conf::default()
+        };
+        let outputs : Vec<Vec<u8>> = [1, 2, 4, 8].iter().map(|&jobs| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build().unwrap();
+            let mut our_output : Vec<u8> = vec![];
+            pool.install(|| {
+                diff_files(&mut our_output, &conf, Some (vec![r"^\d+ (read|write) (\d+)"]),
+                           Some ("[0-3]"), &old, &new).unwrap()
+            });
+            our_output
+        }).collect();
+        assert!(!outputs[0].is_empty());
+        for output in &outputs[1..] {
+            assert_eq!(String::from_utf8_lossy(output), String::from_utf8_lossy(&outputs[0]));
+        }
+    }
+    tmpdir.remove().unwrap()
+}
+
+fn count_common<T : PartialEq + Clone>(diff : &[DiffResult<T>]) -> usize {
+    diff.iter().filter(|d| matches!(d, DiffResult::Common (_))).count()
+}
+
+// Check that the diff results walk through both sequences in order.
+fn check_diff_covers<T : PartialEq + Clone + Debug>(old : &[T], new : &[T],
+                                                     diff : &[DiffResult<T>]) {
+    let mut o = 0;
+    let mut n = 0;
+    for d in diff {
+        match d {
+            DiffResult::Common (el) => {
+                assert_eq!(el.old_index, Some (o));
+                assert_eq!(el.new_index, Some (n));
+                assert_eq!(old[o], new[n]);
+                o += 1;
+                n += 1;
+            },
+            DiffResult::Removed (el) => {
+                assert_eq!(el.old_index, Some (o));
+                o += 1;
+            },
+            DiffResult::Added (el) => {
+                assert_eq!(el.new_index, Some (n));
+                n += 1;
+            },
+        }
+    }
+    assert_eq!((o, n), (old.len(), new.len()));
+}
+
+#[test]
+fn myers_finds_minimal_diffs() {
+    let mut state = 42;
+    for _ in 0..2000 {
+        let old_len = (lcg(&mut state) % 20) as usize;
+        let new_len = (lcg(&mut state) % 20) as usize;
+        let alphabet = 1 + lcg(&mut state) % 6;
+        let old : Vec<u64> = (0..old_len).map(|_| lcg(&mut state) % alphabet).collect();
+        let new : Vec<u64> = (0..new_len).map(|_| lcg(&mut state) % alphabet).collect();
+        let diff = myers::diff(&old, &new);
+        check_diff_covers(&old, &new, &diff);
+        assert_eq!(count_common(&diff), count_common(&lcs_diff::diff(&old, &new)),
+                   "Non-minimal diff for {:?} -> {:?}", old, new);
+    }
+}
+
+#[test]
+fn all_diff_algorithms_produce_valid_diffs() {
+    use conf::DiffAlgorithm;
+    let mut state = 7;
+    for _ in 0..2000 {
+        let old_len = (lcg(&mut state) % 30) as usize;
+        let new_len = (lcg(&mut state) % 30) as usize;
+        let alphabet = 1 + lcg(&mut state) % 10;
+        let old : Vec<u64> = (0..old_len).map(|_| lcg(&mut state) % alphabet).collect();
+        let new : Vec<u64> = (0..new_len).map(|_| lcg(&mut state) % alphabet).collect();
+        for alg in DiffAlgorithm::allowed_values() {
+            let conf = Conf {
+                diff_algorithm : DiffAlgorithm::new(alg),
+                ..Conf::default()
+            };
+            check_diff_covers(&old, &new, &diff_lines(&conf, &old, &new));
+        }
+    }
+}
+
+#[test]
+fn histogram_is_not_quadratic_on_interleaved_changes() {
+    let lines = 100_000;
+    let old : Vec<String> = (0..lines).map(|i| if i % 2 == 0 {
+        format!("same {}", i)
+    } else {
+        format!("old {}", i)
+    }).collect();
+    let new : Vec<String> = (0..lines).map(|i| if i % 2 == 0 {
+        format!("same {}", i)
+    } else {
+        format!("new {}", i)
+    }).collect();
+    let (edits, work) = histogram::edits(&old, &new);
+    let diff = myers::edits_to_results(&old, &new, &edits);
+    check_diff_covers(&old, &new, &diff);
+    assert_eq!(count_common(&diff), lines / 2);
+    // Each line should be looked at about once per level of a balanced
+    // split, not once per line.
+    assert!(work < 20 * (old.len() + new.len()), "Histogram did {} work", work);
+}
+
+#[test]
+fn interned_keys_give_minimal_diffs() {
+    let mut state = 1;
+    for _ in 0..1000 {
+        let alphabet = 1 + lcg(&mut state) % 6;
+        let gen = |state : &mut u64| -> Vec<Vec<u8>> {
+            let len = lcg(state) % 10;
+            (0..len).map(|_| format!("line {}\n", lcg(state) % alphabet).into_bytes())
+                .collect()
+        };
+        let prefix = gen(&mut state);
+        let suffix = gen(&mut state);
+        let old_mid = gen(&mut state);
+        let new_mid = gen(&mut state);
+        let old = [&prefix[..], &old_mid[..], &suffix[..]].concat();
+        let new = [&prefix[..], &new_mid[..], &suffix[..]].concat();
+        let diff = diff_keys(&Conf::default(), &old, &new);
+        check_diff_covers(&old, &new, &diff);
+        assert_eq!(count_common(&diff), count_common(&myers::diff(&old, &new)),
+                   "Non-minimal diff for {:?} -> {:?}", old, new);
+    }
+}
+
+#[test]
+fn colored_output() {
+    for &(format, first, last) in &[
+        ("wdiff",
+         " \x1b[7;31m1\x1b[0m\x1b[7;32m4\x1b[0m a\n",
+         " \x1b[7;31m3\x1b[0m\x1b[7;32m6\x1b[0m c\n"),
+        ("cc",
+         " \x1b[1;33m\\d+\x1b[0m a\n",
+         " \x1b[1;33m\\d+\x1b[0m c\n"),
+    ] {
+        let conf = Conf {
+            color : true,
+            context_format : ContextLineFormat::new(format),
+            ..Conf::default()
+        };
+        let mut expected_output = vec![];
+        expected_output.extend(b"\x1b[36m@@ -1,3 +1,3 @@\x1b[0m\n".iter());
+        expected_output.extend(first.as_bytes());
+        expected_output.extend(b"\x1b[31m-2 b\x1b[0m\n\x1b[32m+5 x\x1b[0m\n".iter());
+        expected_output.extend(last.as_bytes());
+        test_given(&conf, Some (vec![r"^\d+ (\w+)$"]), None,
+                   &["1 a\n", "2 b\n", "3 c\n"], &["4 a\n", "5 x\n", "6 c\n"],
+                   expected_output);
+    }
+}
+
+#[test]
+fn side_by_side_output() {
+    let conf = Conf {
+        output_format : OutputFormat::SideBySide,
+        width : 23,
+        ..Conf::default()
+    };
+    test_given(&conf, Some (vec![r"^\d+ (\w+)$"]), None,
+               &["1 a\n", "2 b\n", "3 c\n", "4 d\n", "5 e\n"],
+               &["1 a\n", "2 x\n", "6 c\n", "7 f\n"],
+               join_lines(vec![
+                   "@@ -1,5 +1,4 @@",
+                   "1 a          1 a",
+                   "2 b        | 2 x",
+                   "{-3} c       {+6} c",
+                   "4 d        | 7 f",
+                   "5 e        <",
+               ]));
+    // A span cut short by the width still gets closed.
+    test_given(&conf, Some (vec![r"^(key\d) "]), None,
+               &["key1 aaaaaaaa\n", "key2 a\n"],
+               &["key1 bbbbbbbb\n", "key3 a\n"],
+               join_lines(vec![
+                   "@@ -1,2 +1,2 @@",
+                   "key1 {-aa}   key1 {+bb}",
+                   "key2 a     | key3 a",
+               ]));
+}
+
+#[test]
+fn side_by_side_shows_whole_files() {
+    // Unless given -c, or streaming.
+    for &(args, context) in &[(&[][..], usize::MAX), (&["-c", "1"][..], 1),
+                              (&["--stream"][..], 3)] {
+        let mut argv = vec!["subdiff", "-y"];
+        argv.extend(args);
+        argv.extend(&["a", "b"]);
+        let (conf, _) = configure(&app().get_matches_from(argv));
+        assert_eq!(conf.context, context, "{:?}", args);
+    }
+    let conf = Conf {
+        output_format : OutputFormat::SideBySide,
+        context : usize::MAX,
+        width : 23,
+        ..Conf::default()
+    };
+    let tmpdir = temporary::Directory::new("side-by-side-test").unwrap();
+    let old = tmpdir.join("old");
+    let new = tmpdir.join("new");
+    fs::create_dir_all(&old).unwrap();
+    fs::create_dir_all(&new).unwrap();
+    write_file(&old.join("changed"), &["1 a\n", "2 b\n", "3 c\n", "4 d\n", "5 e\n"]);
+    write_file(&new.join("changed"), &["1 a\n", "2 b\n", "3 x\n", "4 d\n", "5 e\n"]);
+    write_file(&old.join("same"), &["1 a\n"]);
+    write_file(&new.join("same"), &["1 a\n"]);
+    let mut our_output : Vec<u8> = vec![];
+    let no_res : Option<Vec<&'static str>> = None;
+    let ecode = diff_files(&mut our_output, &conf, no_res, None, &old, &new).unwrap();
+    assert_eq!(ecode, 1);
+    let mut expected = vec![];
+    writeln!(&mut expected, "diff {} {}",
+             old.join("changed").display(), new.join("changed").display()).unwrap();
+    expected.extend(join_lines(vec![
+        "1 a          1 a",
+        "2 b          2 b",
+        "3 c        | 3 x",
+        "4 d          4 d",
+        "5 e          5 e",
+    ]));
+    check_output(&our_output, &expected);
+    tmpdir.remove().unwrap()
+}
+
+#[test]
+fn side_by_side_width_is_checked() {
+    for &(width, ok) in &[("0", false), ("3", false), ("4", false), ("5", true), ("x", false)] {
+        let matches = app().get_matches_from_safe(vec!["subdiff", "-y", "-W", width, "a", "b"]);
+        assert_eq!(matches.is_ok(), ok, "-W {}", width);
+    }
+}
+
+#[test]
+fn json_output() {
+    let tmpdir = temporary::Directory::new("json-test").unwrap();
+    let old = tmpdir.join("old");
+    let new = tmpdir.join("new");
+    write_file(&old, &["1 a\n", "2 \"b\"\n"]);
+    write_file(&new, &["3 a\n", "4 c"]);
+    let conf = Conf {
+        output_format : OutputFormat::Json,
+        ..Conf::default()
+    };
+    let mut our_output : Vec<u8> = vec![];
+    let ecode = diff_files(&mut our_output, &conf, Some (vec![r"^\d+ (.*)$"]), None,
+                           &old, &new).unwrap();
+    assert_eq!(ecode, 1);
+    let expected = format!(
+        "{{\"old_file\":\"{}\",\"new_file\":\"{}\",\
+         \"old_start\":1,\"old_len\":2,\"new_start\":1,\"new_len\":2,\"items\":[\
+         {{\"kind\":\"common\",\"old_line\":1,\"new_line\":1,\
+         \"old_text\":\"1 a\\n\",\"old_key\":\"a\\n\",\
+         \"new_text\":\"3 a\\n\",\"new_key\":\"a\\n\",\
+         \"changes\":[{{\"kind\":\"removed\",\"text\":\"1\"}},\
+         {{\"kind\":\"added\",\"text\":\"3\"}},\
+         {{\"kind\":\"common\",\"text\":\" a\\n\"}}]}},\
+         {{\"kind\":\"removed\",\"old_line\":2,\"new_line\":null,\
+         \"old_text\":\"2 \\\"b\\\"\\n\",\"old_key\":\"\\\"b\\\"\\n\"}},\
+         {{\"kind\":\"added\",\"old_line\":null,\"new_line\":2,\
+         \"new_text\":\"4 c\",\"new_key\":\"c\\n\"}}]}}\n",
+        old.display(), new.display());
+    assert_eq!(String::from_utf8_lossy(&our_output), expected);
+    tmpdir.remove().unwrap()
+}
+
+#[test]
+fn json_output_keeps_invalid_utf8() {
+    let tmpdir = temporary::Directory::new("json-utf8-test").unwrap();
+    let old = tmpdir.join("old");
+    let new = tmpdir.join("new");
+    fs::write(&old, b"1 caf\xc3\xa9\n2 \n").unwrap();
+    fs::write(&new, b"1 caf\xe9\n3 \n").unwrap();
+    let conf = Conf {
+        output_format : OutputFormat::Json,
+        context_tokenization : Char,
+        ..Conf::default()
+    };
+    let mut our_output : Vec<u8> = vec![];
+    let ecode = diff_files(&mut our_output, &conf, Some (vec![r"^(\d+) "]), None,
+                           &old, &new).unwrap();
+    assert_eq!(ecode, 1);
+    let expected = format!(
+        "{{\"old_file\":\"{}\",\"new_file\":\"{}\",\
+         \"old_start\":1,\"old_len\":2,\"new_start\":1,\"new_len\":2,\"items\":[\
+         {{\"kind\":\"common\",\"old_line\":1,\"new_line\":1,\
+         \"old_text\":\"1 caf\u{e9}\\n\",\"old_key\":\"1\\n\",\
+         \"new_text\":\"1 caf\u{fffd}\\n\",\"new_text_b64\":\"MSBjYWbpCg==\",\
+         \"new_key\":\"1\\n\",\
+         \"changes\":[{{\"kind\":\"common\",\"text\":\"1 caf\"}},\
+         {{\"kind\":\"removed\",\"text\":\"\u{e9}\"}},\
+         {{\"kind\":\"added\",\"text\":\"\u{fffd}\",\"text_b64\":\"6Q==\"}},\
+         {{\"kind\":\"common\",\"text\":\"\\n\"}}]}},\
+         {{\"kind\":\"removed\",\"old_line\":2,\"new_line\":null,\
+         \"old_text\":\"2 \\n\",\"old_key\":\"2\\n\"}},\
+         {{\"kind\":\"added\",\"old_line\":null,\"new_line\":2,\
+         \"new_text\":\"3 \\n\",\"new_key\":\"3\\n\"}}]}}\n",
+        old.display(), new.display());
+    assert_eq!(String::from_utf8(our_output).unwrap(), expected);
+    tmpdir.remove().unwrap()
+}
+
+#[test]
+fn scoped_ignores() {
+    let conf = Conf {
+        context : 100,
+        ..Conf::default()
+    };
+    // Addresses only count for mmap, lengths only for write.
+    let scoped = [(0, r"0x[0-9a-f]+"), (1, r"len=\d+")];
+    let sel = Selection::build(&conf, Some (vec![r"^mmap (.*)$", r"^write (.*)$"]), None,
+                               &scoped);
+    let old = ["mmap addr=0x1000 len=10\n", "write len=5 buf=0x1\n"];
+    test_given_selection(&conf, &sel, &old,
+                         &["mmap addr=0x2000 len=10\n", "write len=7 buf=0x1\n"], vec![]);
+    test_given_selection(&conf, &sel, &old,
+                         &["mmap addr=0x2000 len=11\n", "write len=7 buf=0x2\n"],
+                         join_lines(vec![
+                             "@@ -1,2 +1,2 @@",
+                             "-mmap addr=0x1000 len=10",
+                             "-write len=5 buf=0x1",
+                             "+mmap addr=0x2000 len=11",
+                             "+write len=7 buf=0x2",
+                         ]));
+}
+
+#[test]
+fn html_output() {
+    let tmpdir = temporary::Directory::new("html-test").unwrap();
+    let old = tmpdir.join("old");
+    let new = tmpdir.join("new");
+    write_file(&old, &["1 a x\n", "2 <b>\n", "same\n"]);
+    write_file(&new, &["3 a y\n", "4 c\n", "same\n"]);
+    let conf = Conf {
+        output_format : OutputFormat::Html,
+        ..Conf::default()
+    };
+    let mut our_output : Vec<u8> = vec![];
+    let ecode = diff_files(&mut our_output, &conf, Some (vec![r"^\d+ (.*)$"]), Some ("x|y"),
+                           &old, &new).unwrap();
+    assert_eq!(ecode, 1);
+    let our_output = String::from_utf8(our_output).unwrap();
+    assert!(our_output.starts_with("<!DOCTYPE html>\n"));
+    assert!(our_output.ends_with("</html>\n"));
+    let expected = [
+        "<summary>@@ -1,3 +1,3 @@</summary>",
+        "<tr class=\"ctx old\"><td class=\"ln\">1</td><td class=\"ln\"></td>\
+         <td class=\"mk\"> </td><td><span class=\"uns chg\">1</span>\
+         <span class=\"uns\"> </span><span class=\"sel\">a </span>\
+         <span class=\"ign chg\">x</span></td></tr>",
+        "<tr class=\"del\"><td class=\"ln\">2</td><td class=\"ln\"></td>\
+         <td class=\"mk\">-</td><td><span class=\"uns\">2 </span>\
+         <span class=\"sel\">&lt;b&gt;</span></td></tr>",
+        "<tr class=\"ctx\"><td class=\"ln\">3</td><td class=\"ln\">3</td>\
+         <td class=\"mk\"> </td><td>same</td></tr>",
+        "<tr><td class=\"ln\">2</td><td class=\"del\"><span class=\"uns\">2 </span>\
+         <span class=\"sel\">&lt;b&gt;</span></td><td class=\"ln\">2</td>\
+         <td class=\"add\"><span class=\"uns\">4 </span><span class=\"sel\">c</span></td></tr>",
+    ];
+    for row in expected.iter() {
+        assert!(our_output.contains(row), "`{}` not in output", row);
+    }
+    tmpdir.remove().unwrap()
+}
+
+#[test]
+fn regex_engine_prefixes() {
+    let re = engine::Re::build(RegexEngine::Regex, r"(?regex)^(\w+) (\d+)").unwrap();
+    assert_eq!(re.as_str(), r"(?regex)^(\w+) (\d+)");
+    assert_eq!(re.captures_len(), 3);
+    assert_eq!(re.captures(b"foo 12\n"), Some (vec![Some (0..6), Some (0..3), Some (4..6)]));
+    assert_eq!(re.find_ranges(b"a 1 b 2\n"), vec![0..3]);
+    // Lookaround needs the fancy engine.
+    assert!(engine::Re::build(RegexEngine::Regex, r"^(\w+)(?= )").is_err());
+    assert_eq!(engine::Re::build(RegexEngine::Regex, r"(?fancy)^(\w+)(?= )").is_ok(),
+               cfg!(feature = "fancy"));
+    // What used to be a plain RE still is one.
+    let re = engine::Re::build(RegexEngine::Regex, r"fancy:(\w+)").unwrap();
+    assert_eq!(re.captures(b"fancy:x\n"), Some (vec![Some (0..7), Some (6..7)]));
+    let re = engine::Re::build(RegexEngine::Regex, r"\(\?fancy\)(\w+)").unwrap();
+    assert!(re.is_match(b"(?fancy)x\n"));
+    assert_eq!(engine::Re::build(RegexEngine::Fancy, r"^(\w+)").is_ok(), cfg!(feature = "fancy"));
+}
+
+#[cfg(feature = "fancy")]
+#[test]
+fn fancy_regex_engine() {
+    let conf = Conf {
+        context : 100,
+        regex_engine : RegexEngine::Fancy,
+        ..Conf::default()
+    };
+    // Ignore quoted strings, allowing for escaped quotes, and repeated
+    // words. Lines that aren't UTF-8 are never matched.
+    let sel = Selection::build(&conf, Some (vec![r"^say (.*)$"]),
+                               Some (r#""(?:[^"\\]|\\.)*"|\b(\w+) (?=\1\b)"#), &[]);
+    let mut expected = join_lines(vec![
+        "@@ -1,2 +1,2 @@",
+        " say \"a\\\"{-b}{+c}\" {-twice }twice",
+    ]);
+    expected.extend_from_slice(b"-say \"c\" \xff\n+say \"d\" \xff\n");
+    test_given_selection(&conf, &sel,
+                         &[&b"say \"a\\\"b\" twice twice\n"[..], &b"say \"c\" \xff\n"[..]],
+                         &[&b"say \"a\\\"c\" twice\n"[..], &b"say \"d\" \xff\n"[..]],
+                         expected);
+}
+
+#[test]
+fn profiles() {
+    use profile::Profile;
+    for name in Profile::builtin_names() {
+        let profile = Profile::builtin(name).unwrap();
+        assert!(!profile.regexes.is_empty());
+        for re in &profile.regexes {
+            assert!(engine::Re::build(RegexEngine::Regex, re).unwrap().captures_len() > 1);
+        }
+    }
+    let text = "[a]\nregex = '^(\\w+)'\ncontext-format = \"cc\"\n\
+                [b]\nregex = ['^x(.)', '^y(.)']\nignore = 'z'\n\
+                [c]\ncontext-tokenization = \"line\"\n";
+    let a = Profile::find("a", text).unwrap().unwrap();
+    assert_eq!(a.regexes, vec![r"^(\w+)"]);
+    let conf = a.apply(Conf::default());
+    match conf.context_format {
+        CC (Narrow) => (),
+        _ => panic!("The profile's context format was not applied"),
+    }
+    let b = Profile::find("b", text).unwrap().unwrap();
+    assert_eq!(b.regexes, vec![r"^x(.)", r"^y(.)"]);
+    assert_eq!(b.ignore, Some ("z".to_string()));
+    assert!(Profile::find("c", text).is_err());
+    assert!(Profile::find("d", text).unwrap().is_none());
+    assert!(Profile::find("a", "[a\n").is_err());
+    for format in ContextLineFormat::allowed_values() {
+        let text = format!("[f]\ncontext-format = \"{}\"\n", format);
+        assert!(Profile::find("f", &text).unwrap().unwrap().context_format.is_some());
+    }
+    assert!(Profile::find("f", "[f]\ncontext-format = \"ccmin\"\n").is_err());
+
+    let strace = Profile::builtin("strace").unwrap();
+    let conf = strace.apply(Conf {
+        context_format : Old,
+        ..Conf::default()
+    });
+    let sel = Selection::build(&conf, Some (strace.regexes.iter().map(|s| &s[..])),
+                               strace.ignore.as_ref().map(|s| &s[..]), &[]);
+    test_given_selection(&conf, &sel,
+                         &["brk(NULL) = 0x55d1a000\n", "close(3) = 0 <0.000008>\n"],
+                         &["brk(NULL) = 0x56aa0000\n", "close(4) = 0 <0.000008>\n"],
+                         join_lines(vec![
+                             "@@ -1,2 +1,2 @@",
+                             " brk(NULL) = 0x55d1a000",
+                             "-close(3) = 0 <0.000008>",
+                             "+close(4) = 0 <0.000008>",
+                         ]));
+
+    // REs given with -r take precedence over those of the profile, which
+    // only apply to the lines the former don't match.
+    let sel = Selection::build(&conf, Some (vec![r#"^open\("([^"]*)""#]),
+                               strace.ignore.as_ref().map(|s| &s[..]), &[])
+        .with_fallback(&conf, strace.regexes.iter().map(|s| &s[..]));
+    test_given_selection(&conf, &sel,
+                         &["open(\"a\", O_RDONLY) = 3\n", "close(3) = 0 <0.000008>\n"],
+                         &["open(\"a\", O_WRONLY) = 4\n", "close(4) = 0 <0.000008>\n"],
+                         join_lines(vec![
+                             "@@ -1,2 +1,2 @@",
+                             " open(\"a\", O_RDONLY) = 3",
+                             "-close(3) = 0 <0.000008>",
+                             "+close(4) = 0 <0.000008>",
+                         ]));
+}
+
+#[test]
+fn auto_ignore() {
+    let rules : Vec<Substitution> = Substitution::auto_ignore_rules().into_iter().map(|r| {
+        Substitution::parse(r).unwrap()
+    }).collect();
+    let normalized = |line : &str| {
+        String::from_utf8(substitute(&rules, Cow::Borrowed (line.as_bytes())).into_owned()).unwrap()
+    };
+    let cases = [
+        ("2024-05-01T10:00:00.123Z from 10.0.0.1 took 12ms\n",
+         "<timestamp> from <ipv4> took <duration>\n"),
+        ("May  1 10:00:01 host sshd[1234]: key from fe80::1ff:fe23:4567:890a\n",
+         "<timestamp> host sshd[<pid>]: key from <ipv6>\n"),
+        ("[    1.234567] id 123e4567-e89b-12d3-a456-426614174000 at 0xffff8800\n",
+         "[<timestamp>] id <uuid> at <hex>\n"),
+        ("[pid  42] read(3, \"\", 10) = 0 <0.000011>\n",
+         "[<pid>] read(3, \"\", 10) = 0 <duration>\n"),
+        ("std::vector at 23:59:59 on ::1\n",
+         "std::vector at <timestamp> on <ipv6>\n"),
+        ("route 2001:db8:: via ffff::1\n", "route <ipv6> via <ipv6>\n"),
+        // Not IPv6 addresses, just C++ names made of hex letters.
+        ("call Bad::add\n", "call Bad::add\n"),
+        ("call Cafe::feed and ::add\n", "call Cafe::feed and ::add\n"),
+        // Not a PID.
+        ("argv[1] = foo\n", "argv[1] = foo\n"),
+    ];
+    for &(line, expected) in cases.iter() {
+        assert_eq!(normalized(line), expected);
+    }
+}
@@ -2,11 +2,13 @@ use std::io;
 use std::io::prelude::*;
 use std::iter::Peekable;
 use std::marker::PhantomData;
+use std::slice;
 use itertools::Itertools;
 use super::lcs_diff::*;
 use super::Regex;
 use hunked::Hunk;
-use conf::{Conf, CharacterClassExpansion};
+use conf::{Conf, CharacterClassExpansion, ContextLineTokenization};
+use color;
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct Word<'l>(&'l [u8]);
@@ -66,12 +68,32 @@ fn extend(line : &mut Vec<u8>, s : &str) {
     line.extend(s.bytes())
 }
 
+// Changed fragments are either enclosed in wdiff-style markers or, when
+// coloring the output, shown as colored spans.
+fn open_span(line : &mut Vec<u8>, conf : &Conf, marker : &str, style : &[u8]) {
+    if conf.color {
+        line.extend_from_slice(style)
+    } else {
+        extend(line, marker)
+    }
+}
+
+fn close_span(line : &mut Vec<u8>, conf : &Conf) {
+    if conf.color {
+        line.extend_from_slice(color::RESET)
+    } else {
+        line.push(b'}')
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum CharacterClass<T : PartialEq + Clone> {
     White,
     Digit,
     Alpha,
     Word,
+    // A user-defined class, indexing into conf.cc_classes.
+    Class (usize),
     Any (PhantomData<T>),
 }
 
@@ -83,6 +105,7 @@ impl<'l> Into<CharacterClass<Word<'l>>> for CharacterClass<u8> {
             Digit => Digit,
             Alpha => Alpha,
             Word => Word,
+            Class (i) => Class (i),
             Any (_) => Any (PhantomData),
         }
     }
@@ -92,6 +115,7 @@ pub trait HasCharacterClass {
     type Item : Clone + PartialEq;
     fn cc(&self) -> CharacterClass<Self::Item>;
     fn len(&self) -> usize;
+    fn bytes(&self) -> &[u8];
 }
 
 impl HasCharacterClass for u8 {
@@ -111,6 +135,9 @@ impl HasCharacterClass for u8 {
     fn len(&self) -> usize {
         1
     }
+    fn bytes(&self) -> &[u8] {
+        slice::from_ref(self)
+    }
 }
 
 impl<'l> HasCharacterClass for Word<'l> {
@@ -127,11 +154,26 @@ impl<'l> HasCharacterClass for Word<'l> {
     fn len(&self) -> usize {
         self.0.iter().count()
     }
+    fn bytes(&self) -> &[u8] {
+        self.0
+    }
+}
+
+// The user-defined classes are only considered for elements that
+// are members in whole, which tokenize_classes arranges for.
+fn class_of<T>(conf : &Conf, el : &T) -> CharacterClass<T>
+where
+    T: PartialEq + Clone + HasCharacterClass<Item=T> + Writeable,
+{
+    match conf.cc_classes.class_of(el.bytes()) {
+        Some (i) => CharacterClass::Class (i),
+        None => el.cc(),
+    }
 }
 
 impl<T> CharacterClass<T>
 where
-    T: PartialEq + Clone + HasCharacterClass<Item=T>
+    T: PartialEq + Clone + HasCharacterClass<Item=T> + Writeable,
 {
     fn merge(&self, other : &Self) -> Self {
         use self::CharacterClass::*;
@@ -162,20 +204,27 @@ where
                     _ => Any (PhantomData),
                 }
             },
+            Class (i) => {
+                match self {
+                    Class (j) if i == j => Class (*i),
+                    _ => Any (PhantomData),
+                }
+            },
             _ => Any (PhantomData),
         }
     }
-    fn accepts(&self, el : &T) -> bool {
-        let ncc = self.merge(&el.cc());
+    fn accepts(&self, conf : &Conf, el : &T) -> bool {
+        let ncc = self.merge(&class_of(conf, el));
         &ncc == self
     }
-    fn write(&self, out : &mut Write) -> io::Result<()>{
+    fn write(&self, conf : &Conf, out : &mut Write) -> io::Result<()>{
         use self::CharacterClass::*;
         match self {
             White => out.write_all(b"\\s"),
             Digit => out.write_all(b"\\d"),
             Alpha => out.write_all(b"\\a"),
             Word => out.write_all(b"\\w"),
+            Class (i) => write!(out, "\\{}", conf.cc_classes.name(*i)),
             Any (_) => out.write_all(b".")
         }
     }
@@ -196,7 +245,7 @@ fn is_common<T : PartialEq + Clone>(d : &DiffResult<T>) -> bool {
     }
 }
 
-fn narrow_do_common<'a, I, T>(out : &mut Write,
+fn narrow_do_common<'a, I, T>(out : &mut Write, conf : &Conf,
                          prev_cc : Option<CharacterClass<T>>,
                          mut acc : Vec<T>,
                          mut items : Peekable<I>) -> io::Result<()>
@@ -210,20 +259,20 @@ where
         let common = items.peeking_take_while(|d| is_common(d)).map(res_data);
         acc.extend(common);
     }
-    narrow_do_differences(out, prev_cc, acc, items)
+    narrow_do_differences(out, conf, prev_cc, acc, items)
 }
 
-fn skip_common<'a, T, I>(cc : &CharacterClass<T>, items : &mut Peekable<I>)
+fn skip_common<'a, T, I>(conf : &Conf, cc : &CharacterClass<T>, items : &mut Peekable<I>)
 where
     I : Iterator<Item=&'a DiffResult<T>>,
-    T: PartialEq + Clone + HasCharacterClass<Item=T> + 'a,
+    T: PartialEq + Clone + HasCharacterClass<Item=T> + Writeable + 'a,
 {
     items.peeking_take_while(|d| {
         match d {
             DiffResult::Common (_) => {
                 match cc {
                     CharacterClass::Any (_) => false,
-                    ref cc => cc.accepts(&res_data(d)),
+                    ref cc => cc.accepts(conf, &res_data(d)),
                 }
             },
             _ => false,
@@ -231,7 +280,7 @@ where
     }).count();
 }
 
-fn narrow_do_differences<'a, T, I>(out : &mut Write,
+fn narrow_do_differences<'a, T, I>(out : &mut Write, conf : &Conf,
                              prev_cc : Option<CharacterClass<T>>,
                              context_pre : Vec<T>,
                              mut items : Peekable<I>) -> io::Result<()>
@@ -253,18 +302,18 @@ where
         }
     };
     // There is at least one change, we're in business.
-    let cc = res_data(first).cc();
+    let cc = class_of(conf, &res_data(first));
 
     // Go over the changes to determine the CC.
     let cc = items.peeking_take_while(|d| !is_common(d))
-        .map(res_data).fold(cc, |cc : CharacterClass<T>, ch| cc.merge(&ch.cc()));
+        .map(res_data).fold(cc, |cc : CharacterClass<T>, ch| cc.merge(&class_of(conf, &ch)));
 
     // See if any adjacent _common_ characters to our left can be
     // included in the current character class.
     let n_unsummarizable = context_pre.iter().rev().skip_while(|ch| {
         match cc {
             CharacterClass::Any (_) => false,
-            ref cc => cc.accepts(*ch),
+            ref cc => cc.accepts(conf, *ch),
         }
     }).count();
     // Output the common characters to our left that are not
@@ -280,17 +329,19 @@ where
         _ => true
     };
     if print_cc {
-        cc.write(out)?;
+        color::start(out, conf, color::CLASS)?;
+        cc.write(conf, out)?;
         out.write_all(b"+")?;
+        color::end(out, conf)?;
     }
 
     // Omit any adjacent common characters to our right that
     // are compatible with our current CC.
-    skip_common(&cc, &mut items);
-    narrow_do_common(out, Some (cc), vec![], items)
+    skip_common(conf, &cc, &mut items);
+    narrow_do_common(out, conf, Some (cc), vec![], items)
 }
 
-fn wide_do_differences<'a, T, I>(out : &mut Write,
+fn wide_do_differences<'a, T, I>(out : &mut Write, conf : &Conf,
                      mut items : Peekable<I>) -> io::Result<()>
 where
     I : Iterator<Item=&'a DiffResult<T>>,
@@ -314,25 +365,27 @@ where
             None => return Ok (()),
             Some (d) => {
                 count(d);
-                res_data(d).cc()
+                class_of(conf, &res_data(d))
         },
         };
         items.peeking_take_while(|d| !is_common(d))
             .fold(cc, |cc : CharacterClass<T>, d| {
                 count(d);
-                cc.merge(&res_data(d).cc())
+                cc.merge(&class_of(conf, &res_data(d)))
             })
     };
-    cc.write(out)?;
+    color::start(out, conf, color::CLASS)?;
+    cc.write(conf, out)?;
     if nadded == nremoved {
         write!(out, "{{{}}}", nadded)?;
     } else {
         write!(out, "{{{},{}}}", nremoved, nadded)?;
     }
-    wide_do_common(out, items)
+    color::end(out, conf)?;
+    wide_do_common(out, conf, items)
 }
 
-fn wide_do_common<'a, T, I>(out : &mut Write,
+fn wide_do_common<'a, T, I>(out : &mut Write, conf : &Conf,
                      mut items : Peekable<I>) -> io::Result<()>
 where
     I : Iterator<Item=&'a DiffResult<T>>,
@@ -341,12 +394,12 @@ where
 {
     let common : Vec<T> = items.peeking_take_while(|d| is_common(d)).map(res_data).collect();
     common.write_to(out)?;
-    wide_do_differences(out, items)
+    wide_do_differences(out, conf, items)
 }
 
 pub fn intra_line_write_cc<T>(hunk : &Hunk<T>,
                               expansion : CharacterClassExpansion,
-                              _ : &Conf, _ : &[T], _ : &[T],
+                              conf : &Conf, _ : &[T], _ : &[T],
                               out : &mut Write) -> io::Result<()>
 where
     T: PartialEq + Clone + HasCharacterClass<Item=T> + Writeable,
@@ -355,15 +408,15 @@ where
     let items = hunk.items.iter().peekable();
     match expansion {
         Narrow => {
-            narrow_do_common(out, None, vec![], items)
+            narrow_do_common(out, conf, None, vec![], items)
         },
         Wide => {
-            wide_do_common(out, items)
+            wide_do_common(out, conf, items)
         }
     }
 }
 
-pub fn intra_line_write_wdiff<T>(hunk : &Hunk<T>, _ : &Conf,
+pub fn intra_line_write_wdiff<T>(hunk : &Hunk<T>, conf : &Conf,
                                  _ : &[T], _ : &[T],
                                  out : &mut Write) -> io::Result<()>
 where
@@ -381,12 +434,12 @@ where
                         ShowingCommon
                     },
                     DiffResult::Added (el) => {
-                        extend(&mut line, "{+");
+                        open_span(&mut line, conf, "{+", color::ADDED_SPAN);
                         el.data.write_to(&mut line)?;
                         ShowingAdds
                     },
                     DiffResult::Removed(el) => {
-                        extend(&mut line, "{-");
+                        open_span(&mut line, conf, "{-", color::REMOVED_SPAN);
                         el.data.write_to(&mut line)?;
                         ShowingRemoves
                     }
@@ -395,7 +448,7 @@ where
             ShowingAdds => {
                 match d {
                     DiffResult::Common (el) => {
-                        line.push(b'}');
+                        close_span(&mut line, conf);
                         el.data.write_to(&mut line)?;
                         ShowingCommon
                     },
@@ -404,8 +457,8 @@ where
                         ShowingAdds
                     },
                     DiffResult::Removed (el) => {
-                        line.push(b'}');
-                        extend(&mut line, "{-");
+                        close_span(&mut line, conf);
+                        open_span(&mut line, conf, "{-", color::REMOVED_SPAN);
                         el.data.write_to(&mut line)?;
                         ShowingRemoves
                     },
@@ -414,13 +467,13 @@ where
             ShowingRemoves => {
                 match d {
                     DiffResult::Common (el) => {
-                        line.push(b'}');
+                        close_span(&mut line, conf);
                         el.data.write_to(&mut line)?;
                         ShowingCommon
                     },
                     DiffResult::Added (el) => {
-                        line.push(b'}');
-                        extend(&mut line, "{+");
+                        close_span(&mut line, conf);
+                        open_span(&mut line, conf, "{+", color::ADDED_SPAN);
                         el.data.write_to(&mut line)?;
                         ShowingAdds
                     },
@@ -433,18 +486,18 @@ where
         }
     }
     match state {
-        ShowingAdds | ShowingRemoves => line.push(b'}'),
+        ShowingAdds | ShowingRemoves => close_span(&mut line, conf),
         ShowingCommon => (),
     };
     out.write_all(&line)?;
     Ok (())
 }
 
-pub fn tokenize<'l>(line : &'l [u8]) -> Vec<Word<'l>> {
-    let re = Regex::new(r"\b").unwrap();
-
+// Split a line at the word boundaries found by word_boundary (see
+// ContextLineTokenization::Word).
+pub fn tokenize<'l>(word_boundary : &Regex, line : &'l [u8]) -> Vec<Word<'l>> {
     // This is where our 'l comes from.
-    let mut words : Vec<Word<'l>> = re.split(line).map(|w| Word(w)).collect();
+    let mut words : Vec<Word<'l>> = word_boundary.split(line).map(Word).collect();
 
     // Newline handling - splitting on word boundaries unfortunately
     // means that, if the penultimate character of the line is neither
@@ -479,3 +532,69 @@ pub fn tokenize<'l>(line : &'l [u8]) -> Vec<Word<'l>> {
     }
     words
 }
+
+// Split a line into the matches of the user-supplied RE and the
+// (non-empty) text between them. As above, the final newline is always
+// its own word.
+pub fn tokenize_re<'l>(re : &Regex, line : &'l [u8]) -> Vec<Word<'l>> {
+    let text = match line.last() {
+        Some (b'\n') => &line[..line.len() - 1],
+        _ => line,
+    };
+    let mut words = vec![];
+    let mut off = 0;
+    for m in re.find_iter(text) {
+        if m.start() == m.end() {
+            continue
+        }
+        if m.start() > off {
+            words.push(Word(&text[off..m.start()]))
+        }
+        words.push(Word(m.as_bytes()));
+        off = m.end()
+    }
+    if off < text.len() {
+        words.push(Word(&text[off..]))
+    }
+    if text.len() < line.len() {
+        words.push(Word(&line[text.len()..]))
+    }
+    words
+}
+
+// Split a line so that each member of a user-defined character class
+// is a single token and can be summarized by that class. The text in
+// between is tokenized as usual.
+pub fn tokenize_classes<'l>(conf : &Conf, line : &'l [u8]) -> Vec<Word<'l>> {
+    let mut matches : Vec<(usize, usize)> = conf.cc_classes.iter().flat_map(|cl| {
+        cl.re.find_iter(line).filter(|m| m.end() > m.start())
+            .map(|m| (m.start(), m.end())).collect::<Vec<_>>()
+    }).collect();
+    // On overlap, the leftmost match wins, then the earliest class.
+    matches.sort_by_key(|&(start, _)| start);
+    let between = |words : &mut Vec<Word<'l>>, text : &'l [u8]| {
+        match conf.context_tokenization {
+            ContextLineTokenization::Char => {
+                words.extend((0..text.len()).map(|i| Word(&text[i..i + 1])))
+            },
+            ContextLineTokenization::Word (ref wb) => {
+                words.extend(tokenize(wb, text).into_iter().filter(|w| w.len() > 0))
+            },
+            ContextLineTokenization::Re (ref re) => {
+                words.extend(tokenize_re(re, text))
+            },
+        }
+    };
+    let mut words = vec![];
+    let mut off = 0;
+    for (start, end) in matches {
+        if start < off {
+            continue
+        }
+        between(&mut words, &line[off..start]);
+        words.push(Word(&line[start..end]));
+        off = end
+    }
+    between(&mut words, &line[off..]);
+    words
+}